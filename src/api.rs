@@ -2,16 +2,28 @@ use crate::types;
 
 pub struct GPT3Client {
     token: String,
+    /// Where the api lives, anything OpenAI compatible works (like a local llama.cpp server)
+    base_url: String,
 }
 
 impl GPT3Client {
+    /// Reads `OPENAI_API_BASE`, which defaults to OpenAI's own api
     pub fn new(token: &str) -> GPT3Client {
+        GPT3Client::with_base_url(
+            token,
+            &*std::env::var("OPENAI_API_BASE")
+                .unwrap_or_else(|_| String::from("https://api.openai.com/v1")),
+        )
+    }
+
+    pub fn with_base_url(token: &str, base_url: &str) -> GPT3Client {
         GPT3Client {
             token: if token.starts_with("Bearer") {
                 token.to_string()
             } else {
                 format!("Bearer {}", &token)
             },
+            base_url: base_url.trim_end_matches('/').to_string(),
         }
     }
 }
@@ -24,7 +36,8 @@ impl GPT3Client {
     ) -> std::result::Result<types::Completion, surf::http_types::Error> {
         let client = surf::Client::new();
         let mut request = client.post(format!(
-            "https://api.openai.com/v1/engines/{}/completions",
+            "{}/engines/{}/completions",
+            self.base_url,
             model.to_string()
         ));
         request = request.set_header("Authorization", self.token.clone());
@@ -41,4 +54,57 @@ impl GPT3Client {
         // serde_json::from_str(&response)?
         request.recv_json().await
     }
+
+    pub async fn get_moderation(
+        &self,
+        params: types::ModerationRequestParams,
+    ) -> std::result::Result<types::Moderation, surf::http_types::Error> {
+        let client = surf::Client::new();
+        let mut request = client.post(format!("{}/moderations", self.base_url));
+        request = request.set_header("Authorization", self.token.clone());
+        request = request.body_json(&params)?;
+        request.recv_json().await
+    }
+
+    pub async fn get_embeddings(
+        &self,
+        params: types::EmbeddingRequestParams,
+    ) -> std::result::Result<types::Embeddings, surf::http_types::Error> {
+        let client = surf::Client::new();
+        let mut request = client.post(format!("{}/embeddings", self.base_url));
+        request = request.set_header("Authorization", self.token.clone());
+        request = request.body_json(&params)?;
+        request.recv_json().await
+    }
+
+    pub async fn get_chat_completion(
+        &self,
+        params: types::ChatCompletionRequestParams,
+    ) -> std::result::Result<types::ChatCompletion, surf::http_types::Error> {
+        let client = surf::Client::new();
+        let mut request = client.post(format!("{}/chat/completions", self.base_url));
+        request = request.set_header("Authorization", self.token.clone());
+        request = request.body_json(&params)?;
+        request.recv_json().await
+    }
+
+    pub async fn get_image_generation(
+        &self,
+        params: types::ImageGenerationRequestParams,
+    ) -> std::result::Result<types::ImageGeneration, surf::http_types::Error> {
+        let client = surf::Client::new();
+        let mut request = client.post(format!("{}/images/generations", self.base_url));
+        request = request.set_header("Authorization", self.token.clone());
+        request = request.body_json(&params)?;
+        request.recv_json().await
+    }
+
+    /// Fetches a generated file, the urls handed out by the api don't need the token
+    pub async fn download(
+        &self,
+        url: &str,
+    ) -> std::result::Result<Vec<u8>, surf::http_types::Error> {
+        let client = surf::Client::new();
+        client.get(url).recv_bytes().await
+    }
 }
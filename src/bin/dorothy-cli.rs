@@ -0,0 +1,123 @@
+//! Talks to the conversation engine from a terminal, for trying out personas without Discord.
+//! Point `OPENAI_API_BASE` at a local OpenAI compatible server (and set `MEMORY_EMBEDDER=local`)
+//! to run it offline.
+
+use async_trait::async_trait;
+use dorothy::{
+    engine::Engine,
+    history::ChatMedium,
+    permissions::PermissionLevel,
+    transport::{IncomingMessage, OutgoingFile, SendResult, Transport},
+};
+use std::io::{BufRead, Write};
+
+const HELP: &str = "Type to talk as the current speaker, lines starting with ! are commands.
+/as <name> [text]  say one line as <name>, or switch to <name> if there's no text
+/save <path>       write the conversation to a json file
+/load <path>       pick up a conversation saved with /save
+/help              show this
+/quit              leave";
+
+/// Prints replies to stdout
+struct TerminalTransport {
+    ai_name: String,
+}
+
+#[async_trait]
+impl Transport for TerminalTransport {
+    async fn send(&self, _medium: &ChatMedium, text: &str, files: &[OutgoingFile]) -> SendResult {
+        if !text.is_empty() {
+            println!("{}: {}", self.ai_name, text);
+        }
+        for file in files {
+            std::fs::write(&*file.filename, &file.data)?;
+            println!("[{} saved {}]", self.ai_name, file.filename);
+        }
+        Ok(())
+    }
+
+    fn mention(&self, user_id: &str) -> String {
+        format!("@{}", user_id)
+    }
+}
+
+fn prompt(speaker: &str) {
+    print!("{}> ", speaker);
+    if let Err(why) = std::io::stdout().flush() {
+        eprintln!("Failed to flush stdout: {:?}", &why);
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    dotenv::dotenv().ok();
+    let gpt3_token = std::env::var("GPT3_TOKEN").unwrap_or_default();
+    let ai_name = std::env::var("AI_NAME").unwrap_or_else(|_| String::from("Dorothy"));
+    let engine = Engine::from_env(&*gpt3_token);
+    engine.set_name(&*ai_name).await;
+    let transport = TerminalTransport {
+        ai_name: engine.get_name().await,
+    };
+    // a guild channel, so speakers show up under their own names
+    let medium = ChatMedium::from_ids(Some(0), 0);
+    let mut speaker = std::env::var("USER").unwrap_or_else(|_| String::from("You"));
+
+    println!("{}", HELP);
+    prompt(&*speaker);
+    let stdin = std::io::stdin();
+    for line in stdin.lock().lines() {
+        let line = line?;
+        let line = line.trim();
+        let (author, text) = if let Some(rest) = line.strip_prefix("/as ") {
+            let rest = rest.trim();
+            match rest.find(' ') {
+                Some(idx) => (rest[..idx].to_string(), rest[idx..].trim().to_string()),
+                None => {
+                    speaker = rest.to_string();
+                    prompt(&*speaker);
+                    continue;
+                }
+            }
+        } else if let Some(path) = line.strip_prefix("/save ") {
+            match engine.save_transcript(&medium, path.trim()).await {
+                Ok(()) => println!("[Saved to {}]", path.trim()),
+                Err(why) => println!("Failed to save {}: {}", path.trim(), &why),
+            }
+            prompt(&*speaker);
+            continue;
+        } else if let Some(path) = line.strip_prefix("/load ") {
+            match engine.load_transcript(&medium, false, path.trim()).await {
+                Ok(()) => println!("[Loaded {}]", path.trim()),
+                Err(why) => println!("Failed to load {}: {}", path.trim(), &why),
+            }
+            prompt(&*speaker);
+            continue;
+        } else if line == "/help" {
+            println!("{}", HELP);
+            prompt(&*speaker);
+            continue;
+        } else if line == "/quit" {
+            break;
+        } else {
+            (speaker.clone(), line.to_string())
+        };
+        if !text.is_empty() {
+            let message = IncomingMessage {
+                medium,
+                is_private: false,
+                author_id: author.clone(),
+                author_name: author,
+                disambiguator: String::from("cli"),
+                // whoever is at the terminal runs the bot
+                author_level: PermissionLevel::Owner,
+                text,
+                attachments: Vec::new(),
+                replying_to: None,
+            };
+            engine.handle(&transport, message).await;
+        }
+        engine.run_due(&transport).await;
+        prompt(&*speaker);
+    }
+    Ok(())
+}
@@ -0,0 +1,448 @@
+//! Runs the conversation engine on IRC. Channels map onto guild mediums of the network, private
+//! queries onto private ones. Reads `IRC_SERVER`, `IRC_PORT`, `IRC_NICK`, `IRC_PASSWORD` and
+//! `IRC_CHANNELS` (comma separated). Users are known by their services account where the server
+//! tells (IRCv3 `account-tag`, `account-notify` and `extended-join`), so `BOT_OWNERS` takes
+//! account names. Users who aren't logged in are known by their whole `nick!user@host` mask, never
+//! by their nick alone. Channel operators count as moderators.
+
+use async_trait::async_trait;
+use dorothy::{
+    engine::Engine,
+    history::ChatMedium,
+    permissions::PermissionLevel,
+    transport::{IncomingMessage, OutgoingFile, SendResult, Transport},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, RwLock as StdRwLock},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, WriteHalf},
+    net::TcpStream,
+    sync::{Mutex, RwLock},
+};
+
+/// Longest message text sent in one line, leaving room for the prefix servers add in front
+const MAX_LINE_BYTES: usize = 400;
+
+/// The IRCv3 capabilities asked for, all of them about knowing who is logged in as whom
+const CAPABILITIES: &[&str] = &["account-tag", "account-notify", "extended-join"];
+
+/// A line from the server, like `@account=alice :nick!user@host PRIVMSG #channel :hello`
+struct IrcLine<'a> {
+    tags: Vec<(&'a str, &'a str)>,
+    prefix: Option<&'a str>,
+    command: &'a str,
+    params: Vec<&'a str>,
+}
+
+impl<'a> IrcLine<'a> {
+    fn tag(&self, key: &str) -> Option<&'a str> {
+        self.tags
+            .iter()
+            .find(|(tag, _)| *tag == key)
+            .map(|(_, value)| *value)
+    }
+
+    fn nick(&self) -> Option<&'a str> {
+        self.prefix.and_then(|prefix| prefix.split('!').next())
+    }
+}
+
+fn parse_line(line: &str) -> IrcLine {
+    let (tags, line) = match line.strip_prefix('@') {
+        Some(rest) => match rest.find(' ') {
+            Some(idx) => (&rest[..idx], rest[idx + 1..].trim_start()),
+            None => (rest, ""),
+        },
+        None => ("", line),
+    };
+    let tags = tags
+        .split(';')
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| {
+            let mut parts = tag.splitn(2, '=');
+            (parts.next().unwrap_or(""), parts.next().unwrap_or(""))
+        })
+        .collect();
+    let (prefix, mut rest) = match line.strip_prefix(':') {
+        Some(rest) => match rest.find(' ') {
+            Some(idx) => (Some(&rest[..idx]), rest[idx + 1..].trim_start()),
+            None => (Some(rest), ""),
+        },
+        None => (None, line),
+    };
+    let command = match rest.find(' ') {
+        Some(idx) => {
+            let command = &rest[..idx];
+            rest = rest[idx + 1..].trim_start();
+            command
+        }
+        None => std::mem::take(&mut rest),
+    };
+    let mut params = Vec::new();
+    while !rest.is_empty() {
+        if let Some(trailing) = rest.strip_prefix(':') {
+            params.push(trailing);
+            break;
+        }
+        match rest.find(' ') {
+            Some(idx) => {
+                params.push(&rest[..idx]);
+                rest = rest[idx + 1..].trim_start();
+            }
+            None => {
+                params.push(rest);
+                break;
+            }
+        }
+    }
+    IrcLine {
+        tags,
+        prefix,
+        command,
+        params,
+    }
+}
+
+/// An account name as servers send it, `*` meaning not logged in
+fn logged_in(account: &str) -> Option<&str> {
+    match account {
+        "" | "*" => None,
+        account => Some(account),
+    }
+}
+
+/// Splits `text` into pieces of at most `MAX_LINE_BYTES`, on character boundaries
+fn split_message(text: &str) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut rest = text;
+    while rest.len() > MAX_LINE_BYTES {
+        let mut idx = MAX_LINE_BYTES;
+        while !rest.is_char_boundary(idx) {
+            idx -= 1;
+        }
+        // rather break at a space than in the middle of a word
+        let idx = rest[..idx]
+            .rfind(' ')
+            .filter(|&space| space > 0)
+            .unwrap_or(idx);
+        pieces.push(&rest[..idx]);
+        rest = rest[idx..].trim_start();
+    }
+    if !rest.is_empty() {
+        pieces.push(rest);
+    }
+    pieces
+}
+
+fn is_channel(target: &str) -> bool {
+    target.starts_with('#') || target.starts_with('&')
+}
+
+struct IrcTransport {
+    server: String,
+    writer: Mutex<WriteHalf<TcpStream>>,
+    /// Mediums mapped back to the channel or nick to send to
+    targets: RwLock<HashMap<ChatMedium, String>>,
+    /// Lowercased channel names mapped to the nicks that are operators there
+    operators: RwLock<HashMap<String, HashSet<String>>>,
+    /// Lowercased nicks mapped to the account they're logged in as, from `account-notify` and
+    /// `extended-join`, for servers that don't tag every message
+    accounts: RwLock<HashMap<String, String>>,
+    /// Identities mapped to the nick they last talked under, so they can be mentioned
+    nicks: StdRwLock<HashMap<String, String>>,
+}
+
+impl IrcTransport {
+    async fn send_line(&self, line: &str) -> std::io::Result<()> {
+        let mut writer = self.writer.lock().await;
+        writer.write_all(line.as_bytes()).await?;
+        writer.write_all(b"\r\n").await
+    }
+
+    /// Who sent `line`: their account name if they're logged in, otherwise their whole mask. The
+    /// two can't be mixed up, masks always have a `!` in them.
+    async fn identity(&self, line: &IrcLine<'_>) -> String {
+        let nick = line.nick().unwrap_or("");
+        let account = match line.tag("account").and_then(logged_in) {
+            Some(account) => Some(account.to_string()),
+            None => self
+                .accounts
+                .read()
+                .await
+                .get(&*nick.to_lowercase())
+                .cloned(),
+        };
+        let identity = match account {
+            Some(account) => account,
+            None => line.prefix.unwrap_or(nick).to_string(),
+        };
+        if let Ok(mut nicks) = self.nicks.write() {
+            nicks.insert(identity.clone(), nick.to_string());
+        }
+        identity
+    }
+
+    /// Keeps track of who is logged in as whom from account changes and extended joins
+    async fn track_accounts(&self, line: &IrcLine<'_>) {
+        let nick = match line.nick() {
+            Some(nick) => nick.to_lowercase(),
+            None => return,
+        };
+        let mut accounts = self.accounts.write().await;
+        match (line.command, &*line.params) {
+            ("ACCOUNT", [account]) | ("JOIN", [_, account, ..]) => match logged_in(account) {
+                Some(account) => {
+                    accounts.insert(nick, account.to_string());
+                }
+                None => {
+                    accounts.remove(&*nick);
+                }
+            },
+            ("NICK", [new_nick]) => {
+                if let Some(account) = accounts.remove(&*nick) {
+                    accounts.insert(new_nick.to_lowercase(), account);
+                }
+            }
+            ("QUIT", _) => {
+                accounts.remove(&*nick);
+            }
+            _ => {}
+        }
+    }
+
+    /// The medium a message to `target` from `nick` belongs to, remembered so replies find
+    /// their way back. Private conversations are keyed on who is talking rather than their nick,
+    /// so taking somebody's nick doesn't get you their conversation.
+    async fn medium_for(&self, target: &str, nick: &str, identity: &str) -> (ChatMedium, bool) {
+        let (medium, reply_to, is_private) = if is_channel(target) {
+            (
+                ChatMedium::from_names(Some(&*self.server), &*target.to_lowercase()),
+                target,
+                false,
+            )
+        } else {
+            (
+                ChatMedium::from_names(None, &*format!("{}/{}", self.server, identity)),
+                nick,
+                true,
+            )
+        };
+        self.targets
+            .write()
+            .await
+            .insert(medium, reply_to.to_string());
+        (medium, is_private)
+    }
+
+    async fn is_operator(&self, channel: &str, nick: &str) -> bool {
+        self.operators
+            .read()
+            .await
+            .get(&*channel.to_lowercase())
+            .map_or(false, |operators| operators.contains(nick))
+    }
+
+    /// Keeps track of operators from `NAMES` replies and mode changes
+    async fn track_operators(&self, line: &IrcLine<'_>) {
+        let mut operators = self.operators.write().await;
+        match (line.command, &*line.params) {
+            ("353", [_, _, channel, names]) => {
+                let channel_operators = operators.entry(channel.to_lowercase()).or_default();
+                for name in names.split(' ') {
+                    if let Some(nick) = name.strip_prefix('@') {
+                        channel_operators.insert(nick.to_string());
+                    }
+                }
+            }
+            ("MODE", [channel, modes, nicks @ ..]) if is_channel(channel) => {
+                let adding = modes.starts_with('+');
+                // only plain +o/-o changes, anything mixed in would shift the arguments
+                if modes.chars().skip(1).all(|mode| mode == 'o') {
+                    let channel_operators = operators.entry(channel.to_lowercase()).or_default();
+                    for nick in nicks {
+                        if adding {
+                            channel_operators.insert(nick.to_string());
+                        } else {
+                            channel_operators.remove(*nick);
+                        }
+                    }
+                }
+            }
+            ("NICK", [new_nick]) => {
+                let old_nick = line.prefix.and_then(|prefix| prefix.split('!').next());
+                if let Some(old_nick) = old_nick {
+                    for channel_operators in operators.values_mut() {
+                        if channel_operators.remove(old_nick) {
+                            channel_operators.insert(new_nick.to_string());
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for IrcTransport {
+    async fn send(&self, medium: &ChatMedium, text: &str, files: &[OutgoingFile]) -> SendResult {
+        let target = self
+            .targets
+            .read()
+            .await
+            .get(medium)
+            .cloned()
+            .ok_or("Nobody has talked there since connecting")?;
+        for line in text.lines() {
+            for piece in split_message(line.trim()) {
+                self.send_line(&*format!("PRIVMSG {} :{}", target, piece))
+                    .await?;
+            }
+        }
+        if !files.is_empty() {
+            eprintln!("Not sending {} files, IRC has no attachments", files.len());
+        }
+        Ok(())
+    }
+
+    fn mention(&self, user_id: &str) -> String {
+        let nick = self
+            .nicks
+            .read()
+            .ok()
+            .and_then(|nicks| nicks.get(user_id).cloned())
+            .unwrap_or_else(|| user_id.split('!').next().unwrap_or(user_id).to_string());
+        format!("{}:", nick)
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    dotenv::dotenv().ok();
+    let gpt3_token = std::env::var("GPT3_TOKEN").expect("Missing gpt3 token");
+    let server = std::env::var("IRC_SERVER").expect("Missing irc server");
+    let port = std::env::var("IRC_PORT")
+        .ok()
+        .and_then(|port| port.parse().ok())
+        .unwrap_or(6667);
+    let nick = std::env::var("IRC_NICK").unwrap_or_else(|_| String::from("Dorothy"));
+    let channels = std::env::var("IRC_CHANNELS").unwrap_or_default();
+
+    let engine = Arc::new(Engine::from_env(&*gpt3_token));
+    engine.set_name(&*nick).await;
+    let stream = TcpStream::connect((&*server, port)).await?;
+    let (reader, writer) = tokio::io::split(stream);
+    let transport = Arc::new(IrcTransport {
+        server: server.clone(),
+        writer: Mutex::new(writer),
+        targets: RwLock::new(HashMap::new()),
+        operators: RwLock::new(HashMap::new()),
+        accounts: RwLock::new(HashMap::new()),
+        nicks: StdRwLock::new(HashMap::new()),
+    });
+    for capability in CAPABILITIES {
+        transport
+            .send_line(&*format!("CAP REQ :{}", capability))
+            .await?;
+    }
+    if let Ok(password) = std::env::var("IRC_PASSWORD") {
+        transport.send_line(&*format!("PASS {}", password)).await?;
+    }
+    transport.send_line(&*format!("NICK {}", nick)).await?;
+    transport
+        .send_line(&*format!("USER {} 0 * :{}", nick, nick))
+        .await?;
+    // servers answer each request on their own, whatever they don't support is simply not there
+    transport.send_line("CAP END").await?;
+
+    {
+        let engine = engine.clone();
+        let transport = transport.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::delay_for(Duration::from_secs(30)).await;
+                engine.run_due(&*transport).await;
+            }
+        });
+    }
+
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(raw_line) = lines.next_line().await? {
+        let line = parse_line(&*raw_line);
+        transport.track_operators(&line).await;
+        transport.track_accounts(&line).await;
+        match (line.command, &*line.params) {
+            ("PING", params) => {
+                transport
+                    .send_line(&*format!("PONG :{}", params.first().unwrap_or(&"")))
+                    .await?
+            }
+            // welcome, registration went through
+            ("001", _) => {
+                for channel in channels.split(',').map(str::trim) {
+                    if !channel.is_empty() {
+                        transport.send_line(&*format!("JOIN {}", channel)).await?;
+                        // so scheduled messages can go out before anybody talks
+                        transport.medium_for(channel, &*nick, &*nick).await;
+                    }
+                }
+            }
+            ("PRIVMSG", [target, text]) => {
+                let prefix = line.prefix.unwrap_or("");
+                let mut parts = prefix.splitn(2, '!');
+                let author = parts.next().unwrap_or("").to_string();
+                let user = parts
+                    .next()
+                    .and_then(|user_host| user_host.split('@').next())
+                    .unwrap_or("")
+                    .trim_start_matches('~')
+                    .to_string();
+                if author.is_empty() || author.eq_ignore_ascii_case(&*nick) {
+                    continue;
+                }
+                // CTCP, like /me, is wrapped in \x01
+                let text = text.trim_matches('\u{1}');
+                let text = text
+                    .strip_prefix("ACTION ")
+                    .map_or_else(|| text.to_string(), |action| format!("*{}*", action));
+                let identity = transport.identity(&line).await;
+                let (medium, is_private) = transport.medium_for(target, &*author, &*identity).await;
+                let granted = if !is_private && transport.is_operator(target, &*author).await {
+                    PermissionLevel::Moderator
+                } else {
+                    PermissionLevel::Everyone
+                };
+                let author_level = if text.starts_with('!') {
+                    engine
+                        .permissions()
+                        .level_for(&*identity, &[], granted)
+                        .await
+                } else {
+                    PermissionLevel::Everyone
+                };
+                let message = IncomingMessage {
+                    medium,
+                    is_private,
+                    author_id: identity,
+                    author_name: author,
+                    disambiguator: user,
+                    author_level,
+                    text,
+                    attachments: Vec::new(),
+                    replying_to: None,
+                };
+                let engine = engine.clone();
+                let transport = transport.clone();
+                tokio::spawn(async move {
+                    engine.handle(&*transport, message).await;
+                });
+            }
+            _ => {}
+        }
+    }
+    eprintln!("Disconnected from {}", &server);
+    Ok(())
+}
@@ -0,0 +1,541 @@
+//! Runs the conversation engine on Matrix through the client-server api. Rooms map onto guild
+//! mediums of their homeserver, rooms with only the bot and one other person onto private ones.
+//! Reads `MATRIX_HOMESERVER` (like `https://matrix.org`), `MATRIX_ACCESS_TOKEN` and optionally
+//! `MATRIX_ROOMS`, a comma separated list of the room ids to talk in (every joined room by
+//! default). Invites to those rooms are accepted, or to any room when `MATRIX_ROOMS` is empty
+//! and `MATRIX_AUTOJOIN` is `true`. Power level 100 counts as admin and 50 as moderator.
+
+use async_trait::async_trait;
+use dorothy::{
+    engine::Engine,
+    history::ChatMedium,
+    permissions::PermissionLevel,
+    transport::{
+        Attachment, IncomingMessage, OutgoingFile, ReferencedMessage, SendResult, Transport,
+    },
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::RwLock;
+
+type MatrixResult<T> = Result<T, surf::http_types::Error>;
+
+#[derive(Deserialize, Debug, Default)]
+struct WhoAmI {
+    user_id: String,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct SyncResponse {
+    next_batch: String,
+    #[serde(default)]
+    rooms: Rooms,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct Rooms {
+    #[serde(default)]
+    join: HashMap<String, JoinedRoom>,
+    #[serde(default)]
+    invite: HashMap<String, Value>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct JoinedRoom {
+    #[serde(default)]
+    summary: RoomSummary,
+    #[serde(default)]
+    timeline: Timeline,
+}
+
+/// Only sent when something changed, so the last known counts have to be kept around
+#[derive(Deserialize, Debug, Default)]
+struct RoomSummary {
+    #[serde(rename = "m.joined_member_count")]
+    joined_member_count: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct Timeline {
+    #[serde(default)]
+    events: Vec<RoomEvent>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct RoomEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    sender: String,
+    #[serde(default)]
+    content: Value,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct PowerLevels {
+    #[serde(default)]
+    users: HashMap<String, i64>,
+    #[serde(default)]
+    users_default: i64,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct Upload {
+    content_uri: String,
+}
+
+/// Percent-encodes everything but unreserved characters, room ids are full of `!` and `:`
+fn encode(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// The part after the colon in a room or user id
+fn server_name(id: &str) -> &str {
+    id.splitn(2, ':').nth(1).unwrap_or(id)
+}
+
+/// The part between the sigil and the colon in a user id
+fn localpart(user_id: &str) -> &str {
+    user_id
+        .trim_start_matches('@')
+        .split(':')
+        .next()
+        .unwrap_or(user_id)
+}
+
+/// Replies can carry the message they answer in front of the body, quoted like
+/// "> <@alice:example.org> hello" and followed by a blank line. Cuts it off, anybody can write
+/// whatever they like in there so what is quoted comes from the event replied to instead.
+fn strip_reply_fallback(body: &str) -> &str {
+    let mut rest = body;
+    while rest.starts_with('>') {
+        rest = match rest.find('\n') {
+            Some(idx) => &rest[idx + 1..],
+            None => return body,
+        };
+    }
+    match rest.strip_prefix('\n') {
+        Some(reply) if rest.len() < body.len() => reply,
+        _ => body,
+    }
+}
+
+struct MatrixClient {
+    homeserver: String,
+    token: String,
+    user_id: String,
+    transaction: AtomicU64,
+    /// Mediums mapped back to their room ids
+    rooms: RwLock<HashMap<ChatMedium, String>>,
+    joined_member_counts: RwLock<HashMap<String, usize>>,
+}
+
+impl MatrixClient {
+    fn url(&self, path: &str) -> String {
+        format!("{}/_matrix/client/r0{}", self.homeserver, path)
+    }
+
+    async fn get<T: serde::de::DeserializeOwned>(&self, path: &str) -> MatrixResult<T> {
+        surf::Client::new()
+            .get(self.url(path))
+            .set_header("Authorization", format!("Bearer {}", self.token))
+            .recv_json()
+            .await
+    }
+
+    async fn put(&self, path: &str, body: &Value) -> MatrixResult<Value> {
+        surf::Client::new()
+            .put(self.url(path))
+            .set_header("Authorization", format!("Bearer {}", self.token))
+            .body_json(body)?
+            .recv_json()
+            .await
+    }
+
+    async fn post(&self, path: &str, body: &Value) -> MatrixResult<Value> {
+        surf::Client::new()
+            .post(self.url(path))
+            .set_header("Authorization", format!("Bearer {}", self.token))
+            .body_json(body)?
+            .recv_json()
+            .await
+    }
+
+    async fn send_event(&self, room_id: &str, content: &Value) -> MatrixResult<Value> {
+        let transaction = format!(
+            "{}-{}",
+            chrono::Utc::now().timestamp_millis(),
+            self.transaction.fetch_add(1, Ordering::SeqCst)
+        );
+        self.put(
+            &*format!(
+                "/rooms/{}/send/m.room.message/{}",
+                encode(room_id),
+                transaction
+            ),
+            content,
+        )
+        .await
+    }
+
+    async fn upload(&self, file: &OutgoingFile) -> MatrixResult<String> {
+        let upload: Upload = surf::Client::new()
+            .post(format!(
+                "{}/_matrix/media/r0/upload?filename={}",
+                self.homeserver,
+                encode(&*file.filename)
+            ))
+            .set_header("Authorization", format!("Bearer {}", self.token))
+            .body_bytes(&file.data)
+            .set_header("Content-Type", "image/png")
+            .recv_json()
+            .await?;
+        Ok(upload.content_uri)
+    }
+
+    /// Where to download an `mxc://` uri from
+    fn media_url(&self, mxc_uri: &str) -> Option<String> {
+        let rest = mxc_uri.strip_prefix("mxc://")?;
+        Some(format!(
+            "{}/_matrix/media/r0/download/{}",
+            self.homeserver, rest
+        ))
+    }
+
+    async fn medium_for(&self, room_id: &str) -> (ChatMedium, bool) {
+        let is_private = self.joined_member_counts.read().await.get(room_id) == Some(&2);
+        let medium = if is_private {
+            ChatMedium::from_names(None, room_id)
+        } else {
+            ChatMedium::from_names(Some(server_name(room_id)), room_id)
+        };
+        self.rooms.write().await.insert(medium, room_id.to_string());
+        (medium, is_private)
+    }
+
+    async fn granted_level(&self, room_id: &str, user_id: &str) -> PermissionLevel {
+        let power_levels = self
+            .get::<PowerLevels>(&*format!(
+                "/rooms/{}/state/m.room.power_levels",
+                encode(room_id)
+            ))
+            .await;
+        match power_levels {
+            Ok(power_levels) => {
+                match power_levels
+                    .users
+                    .get(user_id)
+                    .copied()
+                    .unwrap_or(power_levels.users_default)
+                {
+                    level if level >= 100 => PermissionLevel::Admin,
+                    level if level >= 50 => PermissionLevel::Moderator,
+                    _ => PermissionLevel::Everyone,
+                }
+            }
+            Err(why) => {
+                eprintln!("Failed to fetch power levels: {:?}", &why);
+                PermissionLevel::Everyone
+            }
+        }
+    }
+
+    /// The message `event_id` in `room_id`, as what a reply to it refers to
+    async fn referenced(
+        &self,
+        engine: &Engine,
+        room_id: &str,
+        event_id: &str,
+    ) -> Option<ReferencedMessage> {
+        let event = self
+            .get::<RoomEvent>(&*format!(
+                "/rooms/{}/event/{}",
+                encode(room_id),
+                encode(event_id)
+            ))
+            .await;
+        let event = match event {
+            Ok(event) => event,
+            Err(why) => {
+                eprintln!("Failed to fetch the event replied to: {:?}", &why);
+                return None;
+            }
+        };
+        let body = event.content.get("body").and_then(Value::as_str)?;
+        let body = if event
+            .content
+            .pointer("/m.relates_to/m.in_reply_to")
+            .is_some()
+        {
+            strip_reply_fallback(body)
+        } else {
+            body
+        };
+        let author_name = if event.sender == self.user_id {
+            engine.get_name().await
+        } else {
+            localpart(&*event.sender).to_string()
+        };
+        Some(ReferencedMessage {
+            author_name,
+            text: body.trim().to_string(),
+        })
+    }
+
+    /// Turns a room message into an `IncomingMessage`, `None` for anything that isn't worth
+    /// answering
+    async fn incoming(
+        &self,
+        engine: &Engine,
+        room_id: &str,
+        event: RoomEvent,
+    ) -> Option<IncomingMessage> {
+        if event.kind != "m.room.message" || event.sender == self.user_id {
+            return None;
+        }
+        let body = event.content.get("body").and_then(Value::as_str)?;
+        let in_reply_to = event
+            .content
+            .pointer("/m.relates_to/m.in_reply_to/event_id")
+            .and_then(Value::as_str);
+        let body = match in_reply_to {
+            Some(_) => strip_reply_fallback(body),
+            None => body,
+        };
+        let (text, attachments) = match event.content.get("msgtype").and_then(Value::as_str)? {
+            "m.text" => (body.to_string(), Vec::new()),
+            "m.emote" => (format!("*{}*", body), Vec::new()),
+            "m.image" => {
+                let url = event
+                    .content
+                    .get("url")
+                    .and_then(Value::as_str)
+                    .and_then(|uri| self.media_url(uri))?;
+                let attachment = Attachment {
+                    url,
+                    filename: body.to_string(),
+                    size: event
+                        .content
+                        .pointer("/info/size")
+                        .and_then(Value::as_u64)
+                        .unwrap_or(0),
+                    is_image: true,
+                };
+                (String::new(), vec![attachment])
+            }
+            // notices are what other bots send, answering them ends in loops
+            _ => return None,
+        };
+        let replying_to = match in_reply_to {
+            Some(event_id) => self.referenced(engine, room_id, event_id).await,
+            None => None,
+        };
+        let (medium, is_private) = self.medium_for(room_id).await;
+        let author_level = if text.trim_start().starts_with('!') {
+            let granted = self.granted_level(room_id, &*event.sender).await;
+            engine
+                .permissions()
+                .level_for(&*event.sender, &[], granted)
+                .await
+        } else {
+            PermissionLevel::Everyone
+        };
+        Some(IncomingMessage {
+            medium,
+            is_private,
+            author_name: localpart(&*event.sender).to_string(),
+            disambiguator: server_name(&*event.sender).to_string(),
+            author_id: event.sender,
+            author_level,
+            text,
+            attachments,
+            replying_to,
+        })
+    }
+}
+
+#[async_trait]
+impl Transport for MatrixClient {
+    async fn send(&self, medium: &ChatMedium, text: &str, files: &[OutgoingFile]) -> SendResult {
+        let room_id = self
+            .rooms
+            .read()
+            .await
+            .get(medium)
+            .cloned()
+            .ok_or("Not in that room")?;
+        if !text.is_empty() {
+            self.send_event(&*room_id, &json!({"msgtype": "m.text", "body": text}))
+                .await?;
+        }
+        for file in files {
+            let content_uri = self.upload(file).await?;
+            self.send_event(
+                &*room_id,
+                &json!({
+                    "msgtype": "m.image",
+                    "body": file.filename,
+                    "url": content_uri,
+                    "info": {"mimetype": "image/png", "size": file.data.len()}
+                }),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn typing(&self, medium: &ChatMedium) {
+        let room_id = match self.rooms.read().await.get(medium).cloned() {
+            Some(room_id) => room_id,
+            None => return,
+        };
+        if let Err(why) = self
+            .put(
+                &*format!(
+                    "/rooms/{}/typing/{}",
+                    encode(&*room_id),
+                    encode(&*self.user_id)
+                ),
+                &json!({"typing": true, "timeout": 30000}),
+            )
+            .await
+        {
+            eprintln!("Could not send typing notification: {:?}", &why);
+        }
+    }
+
+    fn mention(&self, user_id: &str) -> String {
+        user_id.to_string()
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    dotenv::dotenv().ok();
+    let gpt3_token = std::env::var("GPT3_TOKEN").expect("Missing gpt3 token");
+    let homeserver = std::env::var("MATRIX_HOMESERVER").expect("Missing matrix homeserver");
+    let token = std::env::var("MATRIX_ACCESS_TOKEN").expect("Missing matrix access token");
+    let allowed_rooms = std::env::var("MATRIX_ROOMS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|room_id| !room_id.is_empty())
+        .map(String::from)
+        .collect::<Vec<_>>();
+    let is_allowed = |room_id: &str| {
+        allowed_rooms.is_empty() || allowed_rooms.iter().any(|allowed| allowed == room_id)
+    };
+    let autojoin = std::env::var("MATRIX_AUTOJOIN").map_or(false, |value| value == "true");
+    // anybody can invite the bot, so only rooms somebody picked get joined
+    let should_join = |room_id: &str| {
+        if allowed_rooms.is_empty() {
+            autojoin
+        } else {
+            is_allowed(room_id)
+        }
+    };
+
+    let mut client = MatrixClient {
+        homeserver: homeserver.trim_end_matches('/').to_string(),
+        token,
+        user_id: String::new(),
+        transaction: AtomicU64::new(0),
+        rooms: RwLock::new(HashMap::new()),
+        joined_member_counts: RwLock::new(HashMap::new()),
+    };
+    client.user_id = client.get::<WhoAmI>("/account/whoami").await?.user_id;
+    let engine = Arc::new(Engine::from_env(&*gpt3_token));
+    engine.set_name(localpart(&*client.user_id)).await;
+    println!("{} is connected!", &client.user_id);
+    let client = Arc::new(client);
+
+    {
+        let engine = engine.clone();
+        let client = client.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::delay_for(Duration::from_secs(30)).await;
+                engine.run_due(&*client).await;
+            }
+        });
+    }
+
+    // the first sync only catches up, nothing from before the bot started gets answered
+    let mut since: Option<String> = None;
+    loop {
+        let path = match &since {
+            Some(since) => format!("/sync?timeout=30000&since={}", encode(since)),
+            None => format!(
+                "/sync?timeout=0&filter={}",
+                encode(r#"{"room":{"timeline":{"limit":0}}}"#)
+            ),
+        };
+        let sync = match client.get::<SyncResponse>(&*path).await {
+            Ok(sync) => sync,
+            Err(why) => {
+                eprintln!("Failed to sync: {:?}", &why);
+                tokio::time::delay_for(Duration::from_secs(5)).await;
+                continue;
+            }
+        };
+        for room_id in sync.rooms.invite.keys() {
+            if !should_join(&*room_id) {
+                eprintln!(
+                    "Ignoring the invite to {}, add it to MATRIX_ROOMS (or set MATRIX_AUTOJOIN=true) \
+                     to join",
+                    &room_id
+                );
+                continue;
+            }
+            if let Err(why) = client
+                .post(&*format!("/join/{}", encode(&*room_id)), &json!({}))
+                .await
+            {
+                eprintln!("Failed to join {}: {:?}", &room_id, &why);
+            }
+        }
+        let caught_up = since.is_some();
+        for (room_id, room) in sync.rooms.join {
+            if !is_allowed(&*room_id) {
+                continue;
+            }
+            if let Some(count) = room.summary.joined_member_count {
+                client
+                    .joined_member_counts
+                    .write()
+                    .await
+                    .insert(room_id.clone(), count);
+            }
+            // so scheduled messages can go out before anybody talks
+            client.medium_for(&*room_id).await;
+            if !caught_up {
+                continue;
+            }
+            for event in room.timeline.events {
+                if let Some(message) = client.incoming(&*engine, &*room_id, event).await {
+                    let engine = engine.clone();
+                    let client = client.clone();
+                    tokio::spawn(async move {
+                        engine.handle(&*client, message).await;
+                    });
+                }
+            }
+        }
+        since = Some(sync.next_batch);
+    }
+}
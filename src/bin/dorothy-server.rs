@@ -0,0 +1,21 @@
+//! Runs only the http api, for talking to the engine from other services and for end-to-end
+//! tests. Listens on `API_ADDRESS`, `127.0.0.1:8080` by default. Scheduled messages have nowhere
+//! to go without a chat platform, so they are left for one of the other front-ends.
+
+use dorothy::{engine::Engine, server};
+use std::sync::Arc;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    dotenv::dotenv().ok();
+    let gpt3_token = std::env::var("GPT3_TOKEN").expect("Missing gpt3 token");
+    let address = std::env::var("API_ADDRESS")
+        .unwrap_or_else(|_| String::from("127.0.0.1:8080"))
+        .parse()?;
+    let engine = Engine::from_env(&*gpt3_token);
+    engine
+        .set_name(&*std::env::var("AI_NAME").unwrap_or_else(|_| String::from("Dorothy")))
+        .await;
+    server::serve(Arc::new(engine), address).await;
+    Ok(())
+}
@@ -0,0 +1,113 @@
+/// Parts of the prompt that compete for the context window, declared most important first. The
+/// persona (context, examples and anything else the template always writes) and the tokens
+/// reserved for the reply are never trimmed, so they aren't segments.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Pinned,
+    Summary,
+    Memories,
+    Turns,
+}
+
+/// A part of the prompt made of items that can be dropped one at a time
+pub struct Segment {
+    pub priority: Priority,
+    /// What each item costs in tokens, the ones to drop first at the front
+    pub costs: Vec<usize>,
+    /// How many items at the back are kept no matter what
+    pub min_kept: usize,
+}
+
+/// A rough token count without the model's tokenizer: four ASCII characters to a token, like
+/// English text through the GPT tokenizer, and a token for every other character.
+///
+/// The GPT tokenizer works on UTF-8 bytes and never makes a token of less than a byte, so the
+/// real count is at most `text.len()`. That's where this can be short: ASCII text that doesn't
+/// read like English (numbers, code, long runs of punctuation) can take up to four times the
+/// estimate, and a character outside of ASCII can take up to its two to four bytes in tokens.
+/// Callers should check what they render and leave some headroom.
+pub fn count_tokens(text: &str) -> usize {
+    let ascii = text.chars().filter(char::is_ascii).count();
+    (ascii + 3) / 4 + (text.chars().count() - ascii)
+}
+
+/// Works out how many items to drop from the front of each segment so `fixed` tokens plus
+/// whatever is kept fits in `window`. The lowest priority segments are trimmed first, the
+/// result is in the same order as `segments`. If even the minimum doesn't fit, the minimum is
+/// kept anyway.
+pub fn allocate(window: usize, fixed: usize, segments: &[Segment]) -> Vec<usize> {
+    let mut total = fixed
+        + segments
+            .iter()
+            .flat_map(|segment| &segment.costs)
+            .sum::<usize>();
+    let mut dropped = vec![0; segments.len()];
+    let mut order = (0..segments.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&idx| std::cmp::Reverse(segments[idx].priority));
+    for idx in order {
+        let segment = &segments[idx];
+        let droppable = segment.costs.len().saturating_sub(segment.min_kept);
+        while total > window && dropped[idx] < droppable {
+            total -= segment.costs[dropped[idx]];
+            dropped[idx] += 1;
+        }
+    }
+    if total > window {
+        eprintln!(
+            "The prompt takes {} tokens even trimmed all the way, more than the {} available",
+            total, window
+        );
+    }
+    dropped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Segments out of priority order, with every item costing 10
+    fn segments() -> Vec<Segment> {
+        let segment = |priority, items, min_kept| Segment {
+            priority,
+            costs: vec![10; items],
+            min_kept,
+        };
+        vec![
+            segment(Priority::Turns, 3, 1),
+            segment(Priority::Pinned, 2, 0),
+            segment(Priority::Memories, 2, 0),
+            segment(Priority::Summary, 1, 0),
+        ]
+    }
+
+    #[test]
+    fn drops_the_lowest_priority_first() {
+        let segments = segments();
+        // turns, pinned, memories, summary
+        assert_eq!(allocate(80, 0, &segments), vec![0, 0, 0, 0]);
+        assert_eq!(allocate(70, 0, &segments), vec![1, 0, 0, 0]);
+        assert_eq!(allocate(60, 0, &segments), vec![2, 0, 0, 0]);
+        assert_eq!(allocate(50, 0, &segments), vec![2, 0, 1, 0]);
+        assert_eq!(allocate(40, 0, &segments), vec![2, 0, 2, 0]);
+        assert_eq!(allocate(30, 0, &segments), vec![2, 0, 2, 1]);
+        assert_eq!(allocate(20, 0, &segments), vec![2, 1, 2, 1]);
+        assert_eq!(allocate(15, 5, &segments), vec![2, 2, 2, 1]);
+    }
+
+    #[test]
+    fn keeps_the_minimum_when_nothing_fits() {
+        let segments = segments();
+        assert_eq!(allocate(5, 0, &segments), vec![2, 2, 2, 1]);
+        assert_eq!(allocate(100, 200, &segments), vec![2, 2, 2, 1]);
+        assert_eq!(allocate(0, 0, &[]), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn counts_ascii_by_four_and_everything_else_by_character() {
+        assert_eq!(count_tokens(""), 0);
+        assert_eq!(count_tokens("hi"), 1);
+        assert_eq!(count_tokens("hello there"), 3);
+        assert_eq!(count_tokens("こんにちは"), 5);
+        assert!(count_tokens("🎉🎉") <= "🎉🎉".len());
+    }
+}
@@ -0,0 +1,902 @@
+use crate::{
+    personas::Example,
+    sanitize::{self, RoleMarkerPolicy},
+    schedule, storage,
+    template::PromptTemplate,
+    types,
+    vision::ImageMode,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{collections::HashMap, fmt::Display, ops::RangeInclusive, str::FromStr};
+use tokio::sync::RwLock;
+
+pub const DEFAULT_MAX_TOKENS: usize = 50;
+pub const DEFAULT_CONTEXT_WINDOW: usize = 2048;
+pub const DEFAULT_MAX_IMAGES: usize = 2;
+pub const DEFAULT_MAX_IMAGE_BYTES: u64 = 8 * 1024 * 1024;
+pub const DEFAULT_CONTEXT: &str = "The following is a conversation with an AI named Dorothy. Dorothy has short, red hair, red eyes and extremely pale (almost white) skin. Dorothy appears to have a bubbly, joyful and somewhat flirtatious attitude. She often greets every patron politely and doesn't at any point seem overly aggressive or violent. She takes great pride in her work";
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Configuration {
+    pub context: String,
+    pub top_p: Option<f64>,
+    pub temperature: Option<f64>,
+    pub presence_penalty: Option<f64>,
+    pub frequency_penalty: Option<f64>,
+    pub max_tokens: usize,
+    /// Tokens the model takes in and writes out together, the prompt is trimmed to fit this
+    /// with `max_tokens` left over
+    pub context_window: usize,
+    pub best_of: Option<usize>,
+    pub logprobs: Option<usize>,
+    pub logit_bias: HashMap<String, i32>,
+    #[serde(rename = "stop")]
+    pub stop_sequences: Vec<String>,
+    #[serde(rename = "role_markers")]
+    pub role_marker_policy: RoleMarkerPolicy,
+    /// When set, replies come from this model through the messages-style chat api instead of
+    /// the davinci completions api
+    pub chat_model: Option<String>,
+    pub images: ImageMode,
+    pub max_images: usize,
+    pub max_image_bytes: u64,
+    /// Whether the model is told it can send pictures with `[draw: ...]`
+    pub drawing: bool,
+    /// Whether chat models are offered the registered tools
+    pub tools: bool,
+    /// Seconds without anybody talking after which the conversation is archived and started over
+    pub inactivity_reset: Option<i64>,
+    /// Pauses at least this many seconds long are marked in the prompt, like "[3 hours later]"
+    pub time_gaps: Option<i64>,
+    /// A persona from the personas file, whose context replaces `context`
+    pub persona: Option<String>,
+    /// Example exchanges of the persona, filled in when the configuration is resolved
+    #[serde(skip)]
+    pub examples: Vec<Example>,
+    /// A template from the templates file that decides how prompts are written out
+    pub template: Option<String>,
+    /// The template `template` names, filled in when the configuration is resolved
+    #[serde(skip)]
+    pub prompt_template: PromptTemplate,
+}
+
+impl std::default::Default for Configuration {
+    fn default() -> Self {
+        Configuration {
+            context: String::from(DEFAULT_CONTEXT),
+            top_p: Some(1.0),
+            temperature: Some(0.9),
+            frequency_penalty: Some(0.0),
+            presence_penalty: Some(0.6),
+            max_tokens: DEFAULT_MAX_TOKENS,
+            context_window: DEFAULT_CONTEXT_WINDOW,
+            best_of: None,
+            logprobs: None,
+            logit_bias: HashMap::new(),
+            stop_sequences: Vec::new(),
+            role_marker_policy: RoleMarkerPolicy::Escape,
+            chat_model: None,
+            images: ImageMode::Caption,
+            max_images: DEFAULT_MAX_IMAGES,
+            max_image_bytes: DEFAULT_MAX_IMAGE_BYTES,
+            drawing: false,
+            tools: true,
+            inactivity_reset: None,
+            time_gaps: None,
+            persona: None,
+            examples: Vec::new(),
+            template: None,
+            prompt_template: PromptTemplate::default(),
+        }
+    }
+}
+
+fn optional_str<T: ToString>(value: &Option<T>) -> String {
+    value
+        .as_ref()
+        .map(|val| val.to_string())
+        .unwrap_or_else(|| String::from("Not set"))
+}
+
+fn optional_duration_str(secs: Option<i64>) -> String {
+    secs.map(schedule::describe_duration)
+        .unwrap_or_else(|| String::from("Not set"))
+}
+
+/// Parses a duration like "2h" or "1h30m", an empty value or "off" unsets it
+fn parse_optional_duration(key: &str, value: &str) -> Result<Option<i64>, String> {
+    match value.trim() {
+        "" | "off" => Ok(None),
+        value => schedule::parse_duration(value).map(Some).ok_or_else(|| {
+            format!(
+                "{} is not a valid duration for {}, like 2h or 1h30m",
+                value, key
+            )
+        }),
+    }
+}
+
+/// Parses "on" or "off", an empty value goes back to `default`
+fn parse_switch(value: &str, default: bool) -> Result<bool, String> {
+    match &*value.trim().to_lowercase() {
+        "" => Ok(default),
+        "on" | "true" => Ok(true),
+        "off" | "false" => Ok(false),
+        _ => Err(format!("{} is not one of on or off", value.trim())),
+    }
+}
+
+pub fn switch_str(value: bool) -> &'static str {
+    if value {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+/// Parses `value` and makes sure it falls inside of `range`
+fn parse_in_range<T>(name: &str, value: &str, range: &RangeInclusive<T>) -> Result<T, String>
+where
+    T: FromStr + PartialOrd + Display,
+{
+    let parsed = value
+        .trim()
+        .parse::<T>()
+        .map_err(|_| format!("{} is not a valid value for {}", value.trim(), name))?;
+    if range.contains(&parsed) {
+        Ok(parsed)
+    } else {
+        Err(format!(
+            "{} must be between {} and {} (got {})",
+            name,
+            range.start(),
+            range.end(),
+            parsed
+        ))
+    }
+}
+
+impl Configuration {
+    pub const KEYS: &'static [&'static str] = &[
+        "context",
+        "temperature",
+        "top_p",
+        "frequency_penalty",
+        "presence_penalty",
+        "max_tokens",
+        "context_window",
+        "best_of",
+        "logprobs",
+        "logit_bias",
+        "stop",
+        "role_markers",
+        "chat_model",
+        "images",
+        "max_images",
+        "max_image_bytes",
+        "drawing",
+        "tools",
+        "inactivity_reset",
+        "time_gaps",
+        "persona",
+        "template",
+    ];
+
+    pub fn temperature_str(&self) -> String {
+        optional_str(&self.temperature)
+    }
+    pub fn top_p_str(&self) -> String {
+        optional_str(&self.top_p)
+    }
+    pub fn presence_penalty_str(&self) -> String {
+        optional_str(&self.presence_penalty)
+    }
+    pub fn frequency_penalty_str(&self) -> String {
+        optional_str(&self.frequency_penalty)
+    }
+    pub fn best_of_str(&self) -> String {
+        optional_str(&self.best_of)
+    }
+    pub fn logprobs_str(&self) -> String {
+        optional_str(&self.logprobs)
+    }
+    pub fn logit_bias_str(&self) -> String {
+        if self.logit_bias.is_empty() {
+            return String::from("Not set");
+        }
+        let mut biases = self
+            .logit_bias
+            .iter()
+            .map(|(token, bias)| format!("{}={}", token, bias))
+            .collect::<Vec<_>>();
+        biases.sort();
+        biases.join(", ")
+    }
+    pub fn chat_model_str(&self) -> String {
+        optional_str(&self.chat_model)
+    }
+    pub fn inactivity_reset_str(&self) -> String {
+        optional_duration_str(self.inactivity_reset)
+    }
+    pub fn time_gaps_str(&self) -> String {
+        optional_duration_str(self.time_gaps)
+    }
+    pub fn persona_str(&self) -> String {
+        optional_str(&self.persona)
+    }
+    pub fn template_str(&self) -> String {
+        optional_str(&self.template)
+    }
+    pub fn stop_sequences_str(&self) -> String {
+        if self.stop_sequences.is_empty() {
+            String::from("Not set")
+        } else {
+            format!("{:?}", self.stop_sequences)
+        }
+    }
+
+    /// Sets `key` from the text following a command, an empty value unsets it (or resets it
+    /// to the default for `max_tokens`). `logit_bias` takes `<token id> <bias>` and `stop` adds
+    /// a sequence, with `\n` standing in for a newline. Returns a confirmation message.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<String, String> {
+        let value = value.trim();
+        match key {
+            "context" => {
+                self.context = sanitize::flatten_lines(value);
+                Ok(format!("Context set to:\n```{}```", &self.context))
+            }
+            "temperature" => {
+                self.temperature = if value.is_empty() {
+                    None
+                } else {
+                    Some(parse_in_range(key, value, &types::TEMPERATURE_RANGE)?)
+                };
+                Ok(format!("temperature set to {}", self.temperature_str()))
+            }
+            "top_p" => {
+                self.top_p = if value.is_empty() {
+                    None
+                } else {
+                    Some(parse_in_range(key, value, &types::TOP_P_RANGE)?)
+                };
+                Ok(format!("top_p set to {}", self.top_p_str()))
+            }
+            "frequency_penalty" => {
+                self.frequency_penalty = if value.is_empty() {
+                    None
+                } else {
+                    Some(parse_in_range(key, value, &types::PENALTY_RANGE)?)
+                };
+                Ok(format!(
+                    "frequency_penalty set to {}",
+                    self.frequency_penalty_str()
+                ))
+            }
+            "presence_penalty" => {
+                self.presence_penalty = if value.is_empty() {
+                    None
+                } else {
+                    Some(parse_in_range(key, value, &types::PENALTY_RANGE)?)
+                };
+                Ok(format!(
+                    "presence_penalty set to {}",
+                    self.presence_penalty_str()
+                ))
+            }
+            "max_tokens" => {
+                self.max_tokens = if value.is_empty() {
+                    DEFAULT_MAX_TOKENS
+                } else {
+                    parse_in_range(key, value, &types::MAX_TOKENS_RANGE)?
+                };
+                Ok(format!("max_tokens set to {}", self.max_tokens))
+            }
+            "context_window" => {
+                self.context_window = if value.is_empty() {
+                    DEFAULT_CONTEXT_WINDOW
+                } else {
+                    parse_in_range(key, value, &types::CONTEXT_WINDOW_RANGE)?
+                };
+                Ok(format!("context_window set to {}", self.context_window))
+            }
+            "best_of" => {
+                self.best_of = if value.is_empty() {
+                    None
+                } else {
+                    Some(parse_in_range(key, value, &types::BEST_OF_RANGE)?)
+                };
+                Ok(format!("best_of set to {}", self.best_of_str()))
+            }
+            "logprobs" => {
+                self.logprobs = if value.is_empty() {
+                    None
+                } else {
+                    Some(parse_in_range(key, value, &types::LOGPROBS_RANGE)?)
+                };
+                Ok(format!("logprobs set to {}", self.logprobs_str()))
+            }
+            "logit_bias" => {
+                let mut parts = value.split_whitespace();
+                match (parts.next(), parts.next()) {
+                    (None, _) => self.logit_bias.clear(),
+                    (Some(token), None) => {
+                        self.logit_bias.remove(token);
+                    }
+                    (Some(token), Some(bias)) => {
+                        if token.parse::<u32>().is_err() {
+                            return Err(format!("{} is not a token id", token));
+                        }
+                        let bias = parse_in_range(key, bias, &types::LOGIT_BIAS_RANGE)?;
+                        self.logit_bias.insert(token.to_string(), bias);
+                    }
+                }
+                Ok(format!("logit_bias set to {}", self.logit_bias_str()))
+            }
+            "stop" => {
+                if value.is_empty() {
+                    self.stop_sequences.clear();
+                } else if self.stop_sequences.len() >= types::MAX_STOP_SEQUENCES {
+                    return Err(format!(
+                        "There can be at most {} stop sequences, clear them with !stop",
+                        types::MAX_STOP_SEQUENCES
+                    ));
+                } else {
+                    self.stop_sequences.push(value.replace("\\n", "\n"));
+                }
+                Ok(format!("stop set to {}", self.stop_sequences_str()))
+            }
+            "role_markers" => {
+                self.role_marker_policy = if value.is_empty() {
+                    RoleMarkerPolicy::Escape
+                } else {
+                    RoleMarkerPolicy::parse(value)
+                        .ok_or_else(|| format!("{} is not one of escape, strip or off", value))?
+                };
+                Ok(format!(
+                    "role_markers set to {}",
+                    self.role_marker_policy.to_string()
+                ))
+            }
+            "chat_model" => {
+                self.chat_model = Some(value.to_string()).filter(|value| !value.is_empty());
+                Ok(format!("chat_model set to {}", self.chat_model_str()))
+            }
+            "images" => {
+                self.images = if value.is_empty() {
+                    ImageMode::Caption
+                } else {
+                    ImageMode::parse(value)
+                        .ok_or_else(|| format!("{} is not one of off, caption or native", value))?
+                };
+                Ok(format!("images set to {}", self.images.to_string()))
+            }
+            "max_images" => {
+                self.max_images = if value.is_empty() {
+                    DEFAULT_MAX_IMAGES
+                } else {
+                    parse_in_range(key, value, &types::MAX_IMAGES_RANGE)?
+                };
+                Ok(format!("max_images set to {}", self.max_images))
+            }
+            "max_image_bytes" => {
+                self.max_image_bytes = if value.is_empty() {
+                    DEFAULT_MAX_IMAGE_BYTES
+                } else {
+                    value
+                        .parse()
+                        .map_err(|_| format!("{} is not a valid value for {}", value, key))?
+                };
+                Ok(format!("max_image_bytes set to {}", self.max_image_bytes))
+            }
+            "drawing" => {
+                self.drawing = parse_switch(value, false)?;
+                Ok(format!("drawing set to {}", switch_str(self.drawing)))
+            }
+            "tools" => {
+                self.tools = parse_switch(value, true)?;
+                Ok(format!("tools set to {}", switch_str(self.tools)))
+            }
+            "inactivity_reset" => {
+                self.inactivity_reset = parse_optional_duration(key, value)?;
+                Ok(format!(
+                    "inactivity_reset set to {}",
+                    self.inactivity_reset_str()
+                ))
+            }
+            "time_gaps" => {
+                self.time_gaps = parse_optional_duration(key, value)?;
+                Ok(format!("time_gaps set to {}", self.time_gaps_str()))
+            }
+            "persona" => {
+                self.persona = Some(value.to_string()).filter(|value| !value.is_empty());
+                Ok(format!("persona set to {}", self.persona_str()))
+            }
+            "template" => {
+                self.template = Some(value.to_string()).filter(|value| !value.is_empty());
+                Ok(format!("template set to {}", self.template_str()))
+            }
+            _ => Err(format!("Unknown configuration key {}", key)),
+        }
+    }
+
+    /// Sets `key` from a json value like the ones `ConfigStore::explain` lists. Strings go
+    /// through `set`, so they can be written the way the commands take them.
+    pub fn set_value(&mut self, key: &str, value: Value) -> Result<String, String> {
+        let value = match value {
+            Value::String(value) => return self.set(key, &*value),
+            value => value,
+        };
+        if !Configuration::KEYS.contains(&key) {
+            return Err(format!("Unknown configuration key {}", key));
+        }
+        let mut values = match serde_json::to_value(&*self) {
+            Ok(Value::Object(values)) => values,
+            _ => return Err(String::from("Failed to serialize configuration")),
+        };
+        values.insert(key.to_string(), value.clone());
+        let mut updated = serde_json::from_value::<Configuration>(Value::Object(values))
+            .map_err(|why| format!("{} is not a valid value for {}: {}", value, key, why))?;
+        updated.examples = std::mem::take(&mut self.examples);
+        updated.prompt_template = std::mem::take(&mut self.prompt_template);
+        *self = updated;
+        Ok(format!("{} set to {}", key, self.value_str(key)))
+    }
+
+    /// The value of `key` the way `!config show` prints it
+    pub fn value_str(&self, key: &str) -> String {
+        match key {
+            "context" => self.context.clone(),
+            "temperature" => self.temperature_str(),
+            "top_p" => self.top_p_str(),
+            "frequency_penalty" => self.frequency_penalty_str(),
+            "presence_penalty" => self.presence_penalty_str(),
+            "max_tokens" => self.max_tokens.to_string(),
+            "context_window" => self.context_window.to_string(),
+            "best_of" => self.best_of_str(),
+            "logprobs" => self.logprobs_str(),
+            "logit_bias" => self.logit_bias_str(),
+            "stop" => self.stop_sequences_str(),
+            "role_markers" => self.role_marker_policy.to_string().to_string(),
+            "chat_model" => self.chat_model_str(),
+            "images" => self.images.to_string().to_string(),
+            "max_images" => self.max_images.to_string(),
+            "max_image_bytes" => self.max_image_bytes.to_string(),
+            "drawing" => switch_str(self.drawing).to_string(),
+            "tools" => switch_str(self.tools).to_string(),
+            "inactivity_reset" => self.inactivity_reset_str(),
+            "time_gaps" => self.time_gaps_str(),
+            "persona" => self.persona_str(),
+            "template" => self.template_str(),
+            _ => String::new(),
+        }
+    }
+
+    pub fn completion_params(
+        &self,
+        prompt: String,
+        stop_tokens: Vec<String>,
+    ) -> types::CompletionRequestParams {
+        types::CompletionRequestParams {
+            prompt,
+            presence_penalty: self.presence_penalty,
+            frequency_penalty: self.frequency_penalty,
+            temperature: self.temperature,
+            top_p: self.top_p,
+            max_tokens: self.max_tokens,
+            best_of: self.best_of,
+            logprobs: self.logprobs,
+            logit_bias: self.logit_bias.clone(),
+            stop_tokens: Some(stop_tokens),
+            choices_per_prompt: Some(1),
+        }
+    }
+
+    pub fn chat_params(
+        &self,
+        model: &str,
+        messages: Vec<types::ChatMessage>,
+        stop_tokens: Vec<String>,
+    ) -> types::ChatCompletionRequestParams {
+        types::ChatCompletionRequestParams {
+            model: model.to_string(),
+            messages,
+            presence_penalty: self.presence_penalty,
+            frequency_penalty: self.frequency_penalty,
+            temperature: self.temperature,
+            top_p: self.top_p,
+            max_tokens: self.max_tokens,
+            logit_bias: self.logit_bias.clone(),
+            stop_tokens: Some(stop_tokens),
+            choices_per_prompt: Some(1),
+            tools: Vec::new(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        self.completion_params(String::new(), self.stop_sequences.clone())
+            .validate()?;
+        if !types::CONTEXT_WINDOW_RANGE.contains(&self.context_window) {
+            return Err(format!(
+                "context_window must be between {} and {} (got {})",
+                types::CONTEXT_WINDOW_RANGE.start(),
+                types::CONTEXT_WINDOW_RANGE.end(),
+                self.context_window
+            ));
+        }
+        if self.max_tokens >= self.context_window {
+            return Err(format!(
+                "max_tokens ({}) must leave room for the prompt in context_window ({})",
+                self.max_tokens, self.context_window
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A set of overrides keyed by configuration key, anything missing is inherited
+pub type Layer = Map<String, Value>;
+
+#[derive(Serialize, Deserialize, Default)]
+struct ConfigFile {
+    #[serde(default)]
+    global: Layer,
+    #[serde(default)]
+    guilds: HashMap<u64, Layer>,
+    #[serde(default)]
+    channels: HashMap<u64, Layer>,
+}
+
+/// Where the effective value of a configuration key came from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Source {
+    Default,
+    Global,
+    Guild,
+    Channel,
+}
+
+impl Source {
+    pub fn to_string(&self) -> &'static str {
+        match self {
+            Source::Default => "default",
+            Source::Global => "global",
+            Source::Guild => "guild",
+            Source::Channel => "channel",
+        }
+    }
+}
+
+/// A configuration key as `ConfigStore::explain` lists it
+pub struct ExplainedKey {
+    pub key: &'static str,
+    /// The value in the form `Configuration::set_value` takes back
+    pub value: Value,
+    /// The value the way `!config show` prints it
+    pub shown: String,
+    pub source: Source,
+}
+
+/// Resolves configuration through built-in defaults, the global section of the config file,
+/// guild overrides and channel overrides, in that order. Overrides set through commands are
+/// written back to the config file.
+pub struct ConfigStore {
+    path: String,
+    file: RwLock<ConfigFile>,
+}
+
+impl ConfigStore {
+    pub fn load(path: &str) -> Self {
+        ConfigStore {
+            path: path.to_string(),
+            file: RwLock::new(storage::load_json(path)),
+        }
+    }
+
+    async fn save(&self) {
+        storage::save_json(&self.path, &*self.file.read().await);
+    }
+
+    /// Applies each layer on top of the defaults one key at a time, skipping (and logging) any
+    /// value that doesn't deserialize or falls outside of its valid range
+    fn resolve_layers(
+        layers: &[(Source, Option<&Layer>)],
+    ) -> (Configuration, HashMap<String, Source>) {
+        let mut configuration = Configuration::default();
+        let mut sources = HashMap::new();
+        for (source, layer) in layers {
+            let layer = match layer {
+                Some(layer) => *layer,
+                None => continue,
+            };
+            for (key, value) in layer.iter() {
+                let mut candidate = match serde_json::to_value(&configuration) {
+                    Ok(Value::Object(candidate)) => candidate,
+                    _ => continue,
+                };
+                candidate.insert(key.clone(), value.clone());
+                match serde_json::from_value::<Configuration>(Value::Object(candidate)) {
+                    Ok(resolved) => match resolved.validate() {
+                        Ok(()) => {
+                            configuration = resolved;
+                            sources.insert(key.clone(), *source);
+                        }
+                        Err(why) => eprintln!("Ignoring {} {}: {}", source.to_string(), key, why),
+                    },
+                    Err(why) => eprintln!("Ignoring {} {}: {}", source.to_string(), key, &why),
+                }
+            }
+        }
+        (configuration, sources)
+    }
+
+    async fn resolve_with_sources(
+        &self,
+        guild_id: Option<u64>,
+        channel_id: u64,
+    ) -> (Configuration, HashMap<String, Source>) {
+        let file = self.file.read().await;
+        ConfigStore::resolve_layers(&[
+            (Source::Global, Some(&file.global)),
+            (
+                Source::Guild,
+                guild_id.and_then(|guild_id| file.guilds.get(&guild_id)),
+            ),
+            (Source::Channel, file.channels.get(&channel_id)),
+        ])
+    }
+
+    pub async fn resolve(&self, guild_id: Option<u64>, channel_id: u64) -> Configuration {
+        self.resolve_with_sources(guild_id, channel_id).await.0
+    }
+
+    /// Lists every key with its effective value and the layer it came from
+    pub async fn explain(&self, guild_id: Option<u64>, channel_id: u64) -> Vec<ExplainedKey> {
+        let (configuration, sources) = self.resolve_with_sources(guild_id, channel_id).await;
+        let values = match serde_json::to_value(&configuration) {
+            Ok(Value::Object(values)) => values,
+            _ => Map::new(),
+        };
+        Configuration::KEYS
+            .iter()
+            .map(|&key| ExplainedKey {
+                key,
+                value: values.get(key).cloned().unwrap_or(Value::Null),
+                shown: configuration.value_str(key),
+                source: sources.get(key).copied().unwrap_or(Source::Default),
+            })
+            .collect()
+    }
+
+    /// Sets `key` on top of the effective configuration and records the result as an override
+    /// on the guild (when `channel_id` is `None`) or channel layer
+    async fn set_override(
+        &self,
+        guild_id: Option<u64>,
+        channel_id: Option<u64>,
+        key: &str,
+        value: Value,
+    ) -> Result<String, String> {
+        let mut configuration = match channel_id {
+            Some(channel_id) => self.resolve(guild_id, channel_id).await,
+            None => {
+                let file = self.file.read().await;
+                ConfigStore::resolve_layers(&[
+                    (Source::Global, Some(&file.global)),
+                    (
+                        Source::Guild,
+                        guild_id.and_then(|guild_id| file.guilds.get(&guild_id)),
+                    ),
+                ])
+                .0
+            }
+        };
+        let confirmation = configuration.set_value(key, value)?;
+        configuration.validate()?;
+        let value = match serde_json::to_value(&configuration) {
+            Ok(Value::Object(mut values)) => values.remove(key).unwrap_or(Value::Null),
+            _ => return Err(String::from("Failed to serialize configuration")),
+        };
+        {
+            let mut file = self.file.write().await;
+            let layer = match (guild_id, channel_id) {
+                (_, Some(channel_id)) => file.channels.entry(channel_id).or_default(),
+                (Some(guild_id), None) => file.guilds.entry(guild_id).or_default(),
+                (None, None) => return Err(String::from("Guild overrides need a guild")),
+            };
+            layer.insert(key.to_string(), value);
+        }
+        self.save().await;
+        Ok(confirmation)
+    }
+
+    pub async fn set_channel(
+        &self,
+        guild_id: Option<u64>,
+        channel_id: u64,
+        key: &str,
+        value: &str,
+    ) -> Result<String, String> {
+        self.set_channel_value(guild_id, channel_id, key, Value::from(value))
+            .await
+    }
+
+    /// Like `set_channel`, with a json value as `Configuration::set_value` takes it
+    pub async fn set_channel_value(
+        &self,
+        guild_id: Option<u64>,
+        channel_id: u64,
+        key: &str,
+        value: Value,
+    ) -> Result<String, String> {
+        self.set_override(guild_id, Some(channel_id), key, value)
+            .await
+    }
+
+    pub async fn set_guild(&self, guild_id: u64, key: &str, value: &str) -> Result<String, String> {
+        self.set_override(Some(guild_id), None, key, Value::from(value))
+            .await
+    }
+
+    /// Removes a channel override, returns whether there was one
+    pub async fn unset_channel(&self, channel_id: u64, key: &str) -> bool {
+        let removed = self
+            .file
+            .write()
+            .await
+            .channels
+            .get_mut(&channel_id)
+            .and_then(|layer| layer.remove(key))
+            .is_some();
+        if removed {
+            self.save().await;
+        }
+        removed
+    }
+
+    /// Removes a guild override, returns whether there was one
+    pub async fn unset_guild(&self, guild_id: u64, key: &str) -> bool {
+        let removed = self
+            .file
+            .write()
+            .await
+            .guilds
+            .get_mut(&guild_id)
+            .and_then(|layer| layer.remove(key))
+            .is_some();
+        if removed {
+            self.save().await;
+        }
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Keys with a range, their bounds and a value just outside of each
+    const BOUNDS: &[(&str, &str, &str, &str, &str)] = &[
+        ("temperature", "0", "2", "-0.1", "2.1"),
+        ("top_p", "0", "1", "-0.1", "1.1"),
+        ("frequency_penalty", "-2", "2", "-2.1", "2.1"),
+        ("presence_penalty", "-2", "2", "-2.1", "2.1"),
+        ("max_tokens", "1", "2048", "0", "2049"),
+        ("context_window", "256", "1000000", "255", "1000001"),
+        ("best_of", "1", "20", "0", "21"),
+        ("logprobs", "0", "5", "-1", "6"),
+        ("max_images", "0", "10", "-1", "11"),
+    ];
+
+    #[test]
+    fn set_takes_the_bounds_of_each_range() {
+        for (key, low, high, below, above) in BOUNDS {
+            let mut configuration = Configuration::default();
+            assert!(configuration.set(key, low).is_ok(), "{} {}", key, low);
+            assert!(configuration.set(key, high).is_ok(), "{} {}", key, high);
+            assert!(configuration.set(key, below).is_err(), "{} {}", key, below);
+            assert!(configuration.set(key, above).is_err(), "{} {}", key, above);
+            assert_eq!(configuration.value_str(key), *high, "{}", key);
+        }
+    }
+
+    #[test]
+    fn validate_checks_the_bounds_of_each_range() {
+        for (key, low, high, below, above) in BOUNDS {
+            if *key == "max_images" {
+                // not sent to the api, only `set` checks it
+                continue;
+            }
+            for (value, is_valid) in &[(low, true), (high, true), (below, false), (above, false)] {
+                let mut configuration = Configuration::default();
+                // make room for the largest max_tokens
+                configuration.context_window = 4096;
+                let value = serde_json::from_str::<Value>(value).unwrap();
+                if configuration.set_value(key, value.clone()).is_err() {
+                    // negative values don't deserialize into unsigned fields
+                    assert!(!is_valid, "{} {}", key, value);
+                    continue;
+                }
+                assert_eq!(
+                    configuration.validate().is_ok(),
+                    *is_valid,
+                    "{} {}",
+                    key,
+                    value
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn set_checks_logit_biases_and_stop_sequences() {
+        let mut configuration = Configuration::default();
+        assert!(configuration.set("logit_bias", "50256 -100").is_ok());
+        assert!(configuration.set("logit_bias", "50256 100").is_ok());
+        assert!(configuration.set("logit_bias", "50256 101").is_err());
+        assert!(configuration.set("logit_bias", "token 1").is_err());
+        for stop in 0..types::MAX_STOP_SEQUENCES {
+            assert!(configuration.set("stop", &*stop.to_string()).is_ok());
+        }
+        assert!(configuration.set("stop", "one too many").is_err());
+        assert!(configuration.validate().is_ok());
+    }
+
+    #[test]
+    fn max_tokens_must_leave_room_in_the_context_window() {
+        let mut configuration = Configuration::default();
+        configuration.set("max_tokens", "2047").unwrap();
+        assert!(configuration.validate().is_ok());
+        configuration.set("max_tokens", "2048").unwrap();
+        assert!(configuration.validate().is_err());
+        configuration.set("context_window", "4096").unwrap();
+        assert!(configuration.validate().is_ok());
+        configuration.set("context_window", "256").unwrap();
+        configuration.set("max_tokens", "256").unwrap();
+        assert!(configuration.validate().is_err());
+    }
+
+    #[test]
+    fn resolving_drops_invalid_stored_values() {
+        let layer = |value: Value| value.as_object().unwrap().clone();
+        let global = layer(json!({ "temperature": 0.5, "top_p": 0.5 }));
+        let guild = layer(json!({ "temperature": 3.0, "max_tokens": 2048 }));
+        let channel = layer(json!({ "top_p": "high", "best_of": 2 }));
+        let (configuration, sources) = ConfigStore::resolve_layers(&[
+            (Source::Global, Some(&global)),
+            (Source::Guild, Some(&guild)),
+            (Source::Channel, Some(&channel)),
+        ]);
+        assert_eq!(configuration.temperature, Some(0.5));
+        assert_eq!(configuration.top_p, Some(0.5));
+        assert_eq!(configuration.max_tokens, DEFAULT_MAX_TOKENS);
+        assert_eq!(configuration.best_of, Some(2));
+        assert_eq!(sources.get("temperature"), Some(&Source::Global));
+        assert_eq!(sources.get("top_p"), Some(&Source::Global));
+        assert_eq!(sources.get("max_tokens"), None);
+        assert_eq!(sources.get("best_of"), Some(&Source::Channel));
+    }
+
+    #[tokio::test]
+    async fn overrides_are_validated_before_they_are_saved() {
+        let dir = std::env::temp_dir().join("dorothy-configuration-test");
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        let store = ConfigStore::load(&*dir.join("config.json").to_string_lossy());
+        assert!(store
+            .set_channel(Some(1), 2, "max_tokens", "2048")
+            .await
+            .is_err());
+        assert!(store
+            .set_channel(Some(1), 2, "max_tokens", "100")
+            .await
+            .is_ok());
+        assert!(store.set_guild(1, "max_tokens", "2048").await.is_err());
+        let configuration = store.resolve(Some(1), 2).await;
+        assert_eq!(configuration.max_tokens, 100);
+        assert_eq!(configuration.context_window, DEFAULT_CONTEXT_WINDOW);
+        let reloaded = ConfigStore::load(&*dir.join("config.json").to_string_lossy());
+        assert_eq!(reloaded.resolve(Some(1), 2).await, configuration);
+    }
+}
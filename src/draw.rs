@@ -0,0 +1,84 @@
+use crate::{api, types};
+use lazy_static::lazy_static;
+use regex::Regex;
+
+type DrawResult = Result<Drawing, Box<dyn std::error::Error + Send + Sync>>;
+
+lazy_static! {
+    static ref REQUEST_PATTERN: Regex =
+        Regex::new(r"(?i)\[\s*draw\s*:([^\]]*)\]").expect("Invalid draw pattern");
+}
+
+pub struct Drawing {
+    pub prompt: String,
+    pub data: Vec<u8>,
+}
+
+/// Generates images through the OpenAI image generation endpoint
+pub struct Artist {
+    client: api::GPT3Client,
+    model: String,
+    size: String,
+}
+
+impl Artist {
+    /// Reads `IMAGE_MODEL` and `IMAGE_SIZE`
+    pub fn from_env(gpt3_token: &str) -> Self {
+        Artist {
+            client: api::GPT3Client::new(gpt3_token),
+            model: std::env::var("IMAGE_MODEL").unwrap_or_else(|_| String::from("dall-e-3")),
+            size: std::env::var("IMAGE_SIZE").unwrap_or_else(|_| String::from("1024x1024")),
+        }
+    }
+
+    pub async fn draw(&self, prompt: &str) -> DrawResult {
+        let mut generation = self
+            .client
+            .get_image_generation(types::ImageGenerationRequestParams {
+                model: self.model.clone(),
+                prompt: prompt.to_string(),
+                n: 1,
+                size: self.size.clone(),
+            })
+            .await?;
+        let url = generation
+            .data
+            .pop()
+            .and_then(|image| image.url)
+            .ok_or("Image generation response had no url")?;
+        Ok(Drawing {
+            prompt: prompt.to_string(),
+            data: self.client.download(&*url).await?,
+        })
+    }
+}
+
+/// The line added to the context when drawing is on, telling the model how to send a picture
+pub fn instructions(ai_name: &str) -> String {
+    format!(
+        "{} can send a picture by writing [draw: a description of the picture] in a message.",
+        ai_name
+    )
+}
+
+/// What goes into the chat history in place of an image that was sent
+pub fn describe(ai_name: &str, prompt: &str) -> String {
+    format!("[{} sent an image of {}]", ai_name, prompt)
+}
+
+/// Pulls every `[draw: ...]` out of a reply, returning what's left of the reply and the
+/// descriptions of the pictures asked for
+pub fn extract_requests(text: &str) -> (String, Vec<String>) {
+    let prompts = REQUEST_PATTERN
+        .captures_iter(text)
+        .filter_map(|captures| captures.get(1))
+        .map(|prompt| prompt.as_str().trim().to_string())
+        .filter(|prompt| !prompt.is_empty())
+        .collect();
+    let rest = REQUEST_PATTERN
+        .replace_all(text, "")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    (rest, prompts)
+}
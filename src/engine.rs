@@ -0,0 +1,1482 @@
+use crate::{
+    api,
+    configuration::{self, ConfigStore, Configuration},
+    draw,
+    history::{ChatHistory, ChatMedium, HistoryMap, Quote, Transcript},
+    memory, moderation,
+    permissions::{self, PermissionModel},
+    personas::PersonaStore,
+    pins::PinStore,
+    profiles, sanitize, schedule,
+    template::TemplateStore,
+    tools,
+    transport::{Attachment, IncomingMessage, OutgoingFile, Transport},
+    types, vision,
+};
+use serde::Serialize;
+use serde_json::Value;
+use tokio::sync::RwLock;
+
+/// What the http api shows of a conversation
+#[derive(Serialize)]
+pub struct ConversationView {
+    pub transcript: Transcript,
+    pub tokens_so_far: usize,
+    /// The prompt as the completions api would see it next
+    pub prompt: String,
+}
+
+/// One line per conversation in the dashboard
+#[derive(Serialize)]
+pub struct ConversationSummary {
+    /// Ids are strings, javascript numbers can't hold Discord's
+    pub guild_id: Option<String>,
+    pub channel_id: String,
+    pub persona: Option<String>,
+    pub context: String,
+    pub tokens_so_far: usize,
+    pub lines: usize,
+}
+
+/// Everything a conversation needs, shared by every front-end. Front-ends turn what they receive
+/// into `IncomingMessage`s and hand them to `handle`, replies go out through their `Transport`.
+pub struct Engine {
+    gpt3_client: api::GPT3Client,
+    moderator: moderation::Moderator,
+    permissions: PermissionModel,
+    config_store: ConfigStore,
+    memory_store: memory::MemoryStore,
+    profile_store: profiles::ProfileStore,
+    captioner: Box<dyn vision::Captioner>,
+    artist: draw::Artist,
+    tools: tools::ToolRegistry,
+    scheduler: schedule::Scheduler,
+    personas: PersonaStore,
+    templates: TemplateStore,
+    pin_store: PinStore,
+    history_map: HistoryMap,
+    name: RwLock<Option<String>>,
+}
+
+/// Where the engine keeps what it persists, apart from memories and moderation policies which
+/// their stores are told about themselves
+pub struct StoragePaths {
+    pub config: String,
+    pub profiles: String,
+    pub schedule: String,
+    pub personas: String,
+    pub templates: String,
+    pub pins: String,
+}
+
+impl StoragePaths {
+    /// Reads `CONFIG_PATH`, `PROFILES_PATH`, `SCHEDULE_PATH`, `PERSONAS_PATH`, `TEMPLATES_PATH`
+    /// and `PINS_PATH`
+    pub fn from_env() -> Self {
+        let path =
+            |key: &str, default: &str| std::env::var(key).unwrap_or_else(|_| String::from(default));
+        StoragePaths {
+            config: path("CONFIG_PATH", "config.json"),
+            profiles: path("PROFILES_PATH", "profiles.json"),
+            schedule: path("SCHEDULE_PATH", "schedule.json"),
+            personas: path("PERSONAS_PATH", "personas.json"),
+            templates: path("TEMPLATES_PATH", "templates.json"),
+            pins: path("PINS_PATH", "pins.json"),
+        }
+    }
+}
+
+/// Everything `Engine::new` puts together, the parts that talk to the outside world can be
+/// swapped for local ones or stubs
+pub struct EngineParts {
+    pub gpt3_client: api::GPT3Client,
+    pub moderator: moderation::Moderator,
+    pub permissions: PermissionModel,
+    pub memory_store: memory::MemoryStore,
+    pub captioner: Box<dyn vision::Captioner>,
+    pub artist: draw::Artist,
+    pub tools: tools::ToolRegistry,
+    pub paths: StoragePaths,
+}
+
+impl Engine {
+    pub fn new(parts: EngineParts) -> Self {
+        let paths = parts.paths;
+        Engine {
+            gpt3_client: parts.gpt3_client,
+            moderator: parts.moderator,
+            permissions: parts.permissions,
+            config_store: ConfigStore::load(&*paths.config),
+            memory_store: parts.memory_store,
+            profile_store: profiles::ProfileStore::load(&*paths.profiles),
+            captioner: parts.captioner,
+            artist: parts.artist,
+            tools: parts.tools,
+            scheduler: schedule::Scheduler::load(&*paths.schedule),
+            personas: PersonaStore::load(&*paths.personas),
+            templates: TemplateStore::load(&*paths.templates),
+            pin_store: PinStore::load(&*paths.pins),
+            history_map: HistoryMap::default(),
+            name: RwLock::new(None),
+        }
+    }
+
+    /// Sets everything up from the environment, each part reads what it needs itself
+    pub fn from_env(gpt3_token: &str) -> Self {
+        Engine::new(EngineParts {
+            gpt3_client: api::GPT3Client::new(gpt3_token),
+            moderator: moderation::Moderator::from_env(gpt3_token),
+            permissions: PermissionModel::from_env(),
+            memory_store: memory::MemoryStore::from_env(gpt3_token),
+            captioner: vision::captioner_from_env(gpt3_token),
+            artist: draw::Artist::from_env(gpt3_token),
+            tools: tools::ToolRegistry::from_env(),
+            paths: StoragePaths::from_env(),
+        })
+    }
+
+    /// Adds a platform specific tool, like looking up Discord members
+    pub fn register_tool(&mut self, tool: Box<dyn tools::Tool>) {
+        self.tools.register(tool);
+    }
+
+    pub fn permissions(&self) -> &PermissionModel {
+        &self.permissions
+    }
+
+    /// The name the AI goes by in the transcript, "AI" until one is set
+    pub async fn set_name(&self, name: &str) {
+        self.name
+            .write()
+            .await
+            .replace(sanitize::sanitize_name(name));
+    }
+
+    /// The configuration of a channel with its persona and template applied
+    async fn configuration(&self, guild_id: Option<u64>, channel_id: u64) -> Configuration {
+        let mut configuration = self.config_store.resolve(guild_id, channel_id).await;
+        self.personas.apply(&mut configuration);
+        self.templates.apply(&mut configuration);
+        configuration
+    }
+
+    /// What is pinned in `medium`, for the prompt
+    async fn pinned(&self, medium: &ChatMedium) -> Vec<String> {
+        self.pin_store
+            .list(&*medium.key())
+            .await
+            .into_iter()
+            .map(|pin| pin.text)
+            .collect()
+    }
+
+    pub fn personas(&self) -> &PersonaStore {
+        &self.personas
+    }
+
+    /// Every conversation the engine holds right now
+    pub async fn conversations(&self) -> Vec<ConversationSummary> {
+        self.history_map
+            .history_map
+            .read()
+            .await
+            .iter()
+            .map(|(medium, chat_history_ref)| ConversationSummary {
+                guild_id: medium.guild_id().map(|guild_id| guild_id.to_string()),
+                channel_id: medium.channel_id().to_string(),
+                persona: chat_history_ref.configuration.persona.clone(),
+                context: chat_history_ref.configuration.context.clone(),
+                tokens_so_far: chat_history_ref.tokens_so_far,
+                lines: chat_history_ref.human_chat_log.len() + chat_history_ref.ai_chat_log.len(),
+            })
+            .collect()
+    }
+
+    /// A look at the conversation in `medium`, `None` if nothing has happened there yet
+    pub async fn conversation(&self, medium: &ChatMedium) -> Option<ConversationView> {
+        let ai_name = self.get_name().await;
+        let read_lock = self.history_map.history_map.read().await;
+        let chat_history_ref = read_lock.get(medium)?;
+        Some(ConversationView {
+            transcript: chat_history_ref.transcript(),
+            tokens_so_far: chat_history_ref.tokens_so_far,
+            prompt: chat_history_ref.to_prompt(&*ai_name).await,
+        })
+    }
+
+    /// Every configuration key of `medium` with its value and where it comes from
+    pub async fn explain_configuration(
+        &self,
+        medium: &ChatMedium,
+    ) -> Vec<configuration::ExplainedKey> {
+        self.config_store
+            .explain(medium.guild_id(), medium.channel_id())
+            .await
+    }
+
+    /// Sets a channel override like the config commands do, a new context starts the
+    /// conversation over
+    pub async fn set_configuration(
+        &self,
+        medium: &ChatMedium,
+        key: &str,
+        value: Value,
+    ) -> Result<String, String> {
+        let confirmation = self
+            .config_store
+            .set_channel_value(medium.guild_id(), medium.channel_id(), key, value)
+            .await?;
+        let configuration = self
+            .configuration(medium.guild_id(), medium.channel_id())
+            .await;
+        if let Some(chat_history_ref) = self.history_map.history_map.write().await.get_mut(medium) {
+            chat_history_ref.set_configuration(configuration).await;
+            if key == "context" {
+                chat_history_ref.reset().await;
+            }
+        }
+        Ok(confirmation)
+    }
+
+    /// Clears the conversation in `medium`, returns whether there was one
+    pub async fn reset(&self, medium: &ChatMedium) -> bool {
+        match self.history_map.history_map.write().await.get_mut(medium) {
+            Some(chat_history_ref) => {
+                chat_history_ref.reset().await;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Writes the conversation in `medium` to `path` as json
+    pub async fn save_transcript(
+        &self,
+        medium: &ChatMedium,
+        path: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let read_lock = self.history_map.history_map.read().await;
+        let transcript = read_lock
+            .get(medium)
+            .map(ChatHistory::transcript)
+            .unwrap_or_default();
+        std::fs::write(path, serde_json::to_string_pretty(&transcript)?)?;
+        Ok(())
+    }
+
+    /// Replaces the conversation in `medium` with one saved by `save_transcript`
+    pub async fn load_transcript(
+        &self,
+        medium: &ChatMedium,
+        is_private: bool,
+        path: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let transcript: Transcript = serde_json::from_str(&*std::fs::read_to_string(path)?)?;
+        let configuration = self
+            .configuration(medium.guild_id(), medium.channel_id())
+            .await;
+        let mut write_lock = self.history_map.history_map.write().await;
+        let chat_history_ref = write_lock
+            .entry(*medium)
+            .or_insert_with(|| ChatHistory::new(is_private, configuration.clone()));
+        chat_history_ref.set_configuration(configuration).await;
+        chat_history_ref.set_ai_name(&*self.get_name().await).await;
+        chat_history_ref.load_transcript(transcript).await;
+        Ok(())
+    }
+
+    /// Runs a command or replies to a message, whichever `message` is
+    pub async fn handle(&self, transport: &dyn Transport, message: IncomingMessage) {
+        let medium = message.medium;
+        let guild_key = medium.guild_id();
+        let channel_key = medium.channel_id();
+        self.scheduler.touch(channel_key).await;
+        let configuration = self.configuration(guild_key, channel_key).await;
+        let mut write_lock = self.history_map.history_map.write().await;
+        let chat_history_ref = write_lock
+            .entry(medium)
+            .or_insert_with(|| ChatHistory::new(message.is_private, configuration.clone()));
+        chat_history_ref.set_configuration(configuration).await;
+        chat_history_ref.set_ai_name(&*self.get_name().await).await;
+        chat_history_ref.set_pins(self.pinned(&medium).await).await;
+        self.archive_if_inactive(&medium, chat_history_ref).await;
+        // keep the line breaks around so the prompt builder can tell lines apart
+        let human_content_raw = &*message.text;
+        let human_content_safe_untrimmed = sanitize::flatten_lines(human_content_raw);
+        let human_content_safe = human_content_safe_untrimmed.trim();
+        if human_content_safe.starts_with("!") {
+            eprintln!("parsing custom command");
+            let command = permissions::command_name(human_content_safe);
+            let (permission_command, required_level) =
+                match self.permissions.required_level(human_content_safe) {
+                    Some(found) => found,
+                    None => return,
+                };
+            if message.author_level >= required_level {
+                if human_content_safe.starts_with("!context=") {
+                    let value: String =
+                        human_content_safe.chars().skip("!context=".len()).collect();
+                    match self
+                        .config_store
+                        .set_channel(guild_key, channel_key, "context", &*value)
+                        .await
+                    {
+                        Ok(confirmation) => {
+                            chat_history_ref
+                                .set_configuration(self.configuration(guild_key, channel_key).await)
+                                .await;
+                            chat_history_ref.reset().await;
+                            self.reply(transport, &medium, &*confirmation).await;
+                        }
+                        Err(why) => self.reply(transport, &medium, &*why).await,
+                    }
+                } else if Configuration::KEYS.contains(&command) {
+                    let value: String =
+                        human_content_safe.chars().skip(command.len() + 1).collect();
+                    let response = match self
+                        .config_store
+                        .set_channel(guild_key, channel_key, command, &*value)
+                        .await
+                    {
+                        Ok(confirmation) => confirmation,
+                        Err(why) => why,
+                    };
+                    chat_history_ref
+                        .set_configuration(self.configuration(guild_key, channel_key).await)
+                        .await;
+                    self.reply(transport, &medium, &*response).await;
+                } else if command == "config" {
+                    let args: String = human_content_safe.chars().skip("!config".len()).collect();
+                    let response = self.config_command(&medium, &*args).await;
+                    chat_history_ref
+                        .set_configuration(self.configuration(guild_key, channel_key).await)
+                        .await;
+                    self.reply(transport, &medium, &*response).await;
+                } else if human_content_safe.starts_with("!reset") {
+                    chat_history_ref.reset().await;
+                    self.reply(transport, &medium, "[Chatlog Cleared]").await;
+                } else if human_content_safe.starts_with("!log") {
+                    let ai_name = self.get_name().await;
+                    self.reply(
+                        transport,
+                        &medium,
+                        &*format!("```{}```", chat_history_ref.to_string(&*ai_name).await),
+                    )
+                    .await;
+                } else if command == "remember" {
+                    let fact: String = human_content_safe.chars().skip("!remember".len()).collect();
+                    if fact.trim().is_empty() {
+                        self.reply(transport, &medium, "Usage: !remember <fact>")
+                            .await;
+                    } else {
+                        self.memory_store
+                            .remember(&*medium.key(), fact.trim(), true)
+                            .await;
+                        self.reply(transport, &medium, "[Remembered]").await;
+                    }
+                } else if command == "forget" {
+                    let filter: String = human_content_safe.chars().skip("!forget".len()).collect();
+                    let forgotten = self.memory_store.forget(&*medium.key(), &*filter).await;
+                    self.reply(
+                        transport,
+                        &medium,
+                        &*format!("[Forgot {} memories]", forgotten),
+                    )
+                    .await;
+                } else if command == "pin" {
+                    let fact: String = human_content_safe.chars().skip("!pin".len()).collect();
+                    let fact = match &message.replying_to {
+                        Some(referenced)
+                            if fact.trim().is_empty() && !referenced.text.trim().is_empty() =>
+                        {
+                            format!(
+                                "{} said: {}",
+                                sanitize::sanitize_name(&*referenced.author_name),
+                                sanitize::flatten_lines(referenced.text.trim())
+                            )
+                        }
+                        _ => fact.trim().to_string(),
+                    };
+                    if fact.is_empty() {
+                        self.reply(
+                            transport,
+                            &medium,
+                            "Usage: !pin <fact>, or reply to a message with !pin",
+                        )
+                        .await;
+                    } else {
+                        self.pin_store
+                            .pin(&*medium.key(), &*fact, &*message.author_name)
+                            .await;
+                        chat_history_ref.set_pins(self.pinned(&medium).await).await;
+                        self.reply(transport, &medium, "[Pinned]").await;
+                    }
+                } else if command == "pins" {
+                    let pins = self.pin_store.list(&*medium.key()).await;
+                    let response = if pins.is_empty() {
+                        String::from("Nothing is pinned here")
+                    } else {
+                        pins.iter()
+                            .enumerate()
+                            .map(|(idx, pin)| {
+                                format!("{}. {} (pinned by {})", idx + 1, pin.text, pin.pinned_by)
+                            })
+                            .collect::<Vec<_>>()
+                            .join("\n")
+                    };
+                    self.reply(transport, &medium, &*response).await;
+                } else if command == "unpin" {
+                    let number: String = human_content_safe.chars().skip("!unpin".len()).collect();
+                    let unpinned = match number.trim().parse() {
+                        Ok(number) => self.pin_store.unpin(&*medium.key(), number).await,
+                        Err(_) => None,
+                    };
+                    match unpinned {
+                        Some(pin) => {
+                            chat_history_ref.set_pins(self.pinned(&medium).await).await;
+                            self.reply(transport, &medium, &*format!("[Unpinned {}]", pin.text))
+                                .await;
+                        }
+                        None => {
+                            self.reply(
+                                transport,
+                                &medium,
+                                "Usage: !unpin <pin number>, \"!pins\" lists them",
+                            )
+                            .await
+                        }
+                    }
+                } else if command == "draw" {
+                    let prompt: String = human_content_safe.chars().skip("!draw".len()).collect();
+                    let prompt = prompt.trim();
+                    if prompt.is_empty() {
+                        self.reply(transport, &medium, "Usage: !draw <description>")
+                            .await;
+                    } else {
+                        transport.typing(&medium).await;
+                        let drawings = self.draw(guild_key, vec![prompt.to_string()]).await;
+                        if drawings.is_empty() {
+                            self.reply(transport, &medium, "Failed to draw that").await;
+                        } else if let Err(why) =
+                            transport.send(&medium, "", &*drawing_files(drawings)).await
+                        {
+                            eprintln!("Failed to send drawing: {:?}", &why);
+                        } else {
+                            let ai_name = self.get_name().await;
+                            let human_name = chat_history_ref.register_speaker(
+                                &*message.author_id,
+                                &*message.author_name,
+                                &*message.disambiguator,
+                                &*ai_name,
+                            );
+                            chat_history_ref
+                                .add_human_log(
+                                    &*human_name,
+                                    &*format!("[asks {} to draw {}]", ai_name, prompt),
+                                    Vec::new(),
+                                    None,
+                                )
+                                .await;
+                            chat_history_ref
+                                .add_ai_log(&*draw::describe(&*ai_name, prompt))
+                                .await;
+                        }
+                    }
+                } else if command == "remind" {
+                    let args: String = human_content_safe.chars().skip("!remind".len()).collect();
+                    let response = self
+                        .scheduler
+                        .remind(guild_key, channel_key, &*message.author_id, &*args)
+                        .await;
+                    self.reply(transport, &medium, &*response).await;
+                } else if command == "greeting" {
+                    let args: String = human_content_safe.chars().skip("!greeting".len()).collect();
+                    let response = self
+                        .scheduler
+                        .greeting_command(guild_key, channel_key, &*args)
+                        .await;
+                    self.reply(transport, &medium, &*response).await;
+                } else if command == "idle" {
+                    let args: String = human_content_safe.chars().skip("!idle".len()).collect();
+                    let response = self
+                        .scheduler
+                        .idle_command(guild_key, channel_key, &*args)
+                        .await;
+                    self.reply(transport, &medium, &*response).await;
+                } else if command == "profile" {
+                    let args: String = human_content_safe.chars().skip("!profile".len()).collect();
+                    let response = self
+                        .profile_store
+                        .command(&*message.author_id, &*args)
+                        .await;
+                    self.reply(transport, &medium, &*response).await;
+                } else if command == "why" {
+                    let response = match (
+                        chat_history_ref.ai_chat_log.last(),
+                        &chat_history_ref.last_logprobs,
+                    ) {
+                        (Some(last_reply), Some(logprobs)) => describe_logprobs(last_reply, logprobs),
+                        (None, _) => String::from("Nothing has been said yet"),
+                        (Some(_), None) => String::from(
+                            "The last reply has no token probabilities, turn them on with \"!logprobs 5\"",
+                        ),
+                    };
+                    self.reply(transport, &medium, &*response).await;
+                } else if human_content_safe.starts_with("!moderation") {
+                    let value: String = human_content_safe
+                        .chars()
+                        .skip("!moderation".len())
+                        .collect();
+                    match (guild_key, moderation::Policy::parse(&*value)) {
+                        (Some(guild_id), Some(policy)) => {
+                            self.moderator.set_policy(guild_id, policy).await;
+                            self.reply(
+                                transport,
+                                &medium,
+                                &*format!("Moderation policy set to {}", policy.to_string()),
+                            )
+                            .await;
+                        }
+                        (guild_id, _) => {
+                            let policy = self.moderator.policy(guild_id).await;
+                            self.reply(
+                                transport,
+                                &medium,
+                                &*format!(
+                                    "Moderation policy is {} (options: block, warn, replace)",
+                                    policy.to_string()
+                                ),
+                            )
+                            .await;
+                        }
+                    }
+                } else if human_content_safe.starts_with("!info") {
+                    self.reply(transport, &medium, &*format!(r#"```temperature ({}): Controls randomness. Lowering results in less random completions. As the temperature approaches zero, the model will become more deterministic and repetitive.
+
+    top_p ({}): Controls diversity via nucleus sampling. 0.5 means half of all likelihood-weighted options are considered.
+
+    frequency_penalty ({}): How much to penalize new tokens based on their existing frequency in the text so far. Decreases the model's likelihood to repeat the same line verbatim.
+
+    prescence_penalty ({}): How much to penalize new tokens based on whether they appear in the text so far. Increases the models liklihood to talk about new topics.
+
+    max_tokens ({}): The most tokens a single completion may produce.
+
+    context_window ({}): How many tokens the model takes at once. The prompt is trimmed to fit with max_tokens to spare, oldest lines first, then memories, then the summary of earlier conversations and last of all pins.
+
+    best_of ({}): Generates this many completions server side and returns the one with the highest log probability per token.
+
+    logit_bias ({}): Biases specific token ids from -100 (never) to 100 (always), like "!logit_bias 50256 -100". "!logit_bias 50256" removes a bias.
+
+    stop ({}): Extra sequences that end a completion, added one at a time like "!stop ###". "!stop" clears them.
+
+    role_markers ({}): What to do with messages that try to speak as somebody else, like "Dorothy: ...". escape defuses them, strip drops lines starting with one, off leaves them alone.
+
+    logprobs ({}): Returns the probability of each generated token and this many alternatives (0 to 5), "!why" shows them for the last reply.
+
+    chat_model ({}): Talks through this chat model instead of davinci completions, like "!chat_model gpt-4o-mini". "!chat_model" goes back to completions.
+
+    images ({}): What to do with image attachments. caption describes them in the message, native hands them to the chat model (needs a vision capable chat_model), off ignores them.
+
+    max_images ({}): The most images used from a single message.
+
+    max_image_bytes ({}): Images bigger than this are skipped.
+
+    drawing ({}): Lets the model send pictures by writing [draw: ...], on or off. "!draw <description>" always works.
+
+    tools ({}): Lets chat models call tools like the dice roller, calculator and knowledge base, on or off.
+
+    inactivity_reset ({}): After this long without anybody talking (like "12h"), the conversation is summarized into long-term memory and started over. "off" keeps it forever.
+
+    time_gaps ({}): Pauses at least this long (like "1h") are marked in the prompt, like "[3 hours later]". "off" leaves them out.
+
+    persona ({}): Talks as this persona from the personas file ({}) in place of the context below. "!persona" goes back to the context.
+
+    template ({}): Writes prompts out with this template from the templates file ({}), in place of the persona's. "!template" goes back to the default format.
+
+    Ranges: temperature 0 to 2, top_p 0 to 1, penalties -2 to 2, max_tokens 1 to 2048, context_window 256 to 1000000, best_of 1 to 20
+
+    You can set any property like this: "!top_p 0.5" or "!temperature 0.6", leave the value out to unset it. "!config show" shows where each value comes from and "!config unset top_p" goes back to the inherited value
+
+    The current context is:
+    {}
+    {} tokens so far
+    ```
+                    "#, chat_history_ref.configuration.temperature_str(),
+    chat_history_ref.configuration.top_p_str(),
+    chat_history_ref.configuration.frequency_penalty_str(),
+    chat_history_ref.configuration.presence_penalty_str(),
+    chat_history_ref.configuration.max_tokens,
+    chat_history_ref.configuration.context_window,
+    chat_history_ref.configuration.best_of_str(),
+    chat_history_ref.configuration.logit_bias_str(),
+    chat_history_ref.configuration.stop_sequences_str(),
+    chat_history_ref.configuration.role_marker_policy.to_string(),
+    chat_history_ref.configuration.logprobs_str(),
+    chat_history_ref.configuration.chat_model_str(),
+    chat_history_ref.configuration.images.to_string(),
+    chat_history_ref.configuration.max_images,
+    chat_history_ref.configuration.max_image_bytes,
+    configuration::switch_str(chat_history_ref.configuration.drawing),
+    configuration::switch_str(chat_history_ref.configuration.tools),
+    chat_history_ref.configuration.inactivity_reset_str(),
+    chat_history_ref.configuration.time_gaps_str(),
+    chat_history_ref.configuration.persona_str(),
+    self.personas.list().keys().cloned().collect::<Vec<_>>().join(", "),
+    chat_history_ref.configuration.template_str(),
+    self.templates.list().keys().cloned().collect::<Vec<_>>().join(", "),
+    chat_history_ref.configuration.context,
+    chat_history_ref.tokens_so_far,
+                    )).await
+                }
+            } else {
+                self.reply(
+                    transport,
+                    &medium,
+                    &*format!(
+                        "You need to be {} to use !{}",
+                        required_level.description(),
+                        permission_command
+                    ),
+                )
+                .await;
+            }
+            return;
+        }
+
+        let human_content_safe = match self
+            .moderator
+            .review(guild_key, moderation::Source::Input, human_content_safe)
+            .await
+        {
+            moderation::Action::Allow => human_content_raw.trim().to_string(),
+            moderation::Action::Withhold => return,
+            moderation::Action::Warn(notice) => {
+                self.reply(transport, &medium, &*notice).await;
+                return;
+            }
+            moderation::Action::Replace(replacement) => replacement,
+        };
+
+        let ai_name = self.get_name().await;
+        let human_name = chat_history_ref.register_speaker(
+            &*message.author_id,
+            &*message.author_name,
+            &*message.disambiguator,
+            &*ai_name,
+        );
+
+        let (human_content_safe, images) = self
+            .attach_images(
+                &*message.attachments,
+                &chat_history_ref.configuration,
+                human_content_safe,
+            )
+            .await;
+        let replying_to = message
+            .replying_to
+            .as_ref()
+            .map(|referenced| Quote::new(&*referenced.author_name, &*referenced.text));
+        chat_history_ref
+            .add_human_log(&*human_name, &*human_content_safe, images, replying_to)
+            .await;
+
+        let profiles = self
+            .profile_store
+            .get(&*message.author_id)
+            .await
+            .describe(&*human_name)
+            .into_iter()
+            .collect();
+        chat_history_ref.set_profiles(profiles).await;
+
+        let medium_key = medium.key();
+        let memories = self
+            .memory_store
+            .recall(&*medium_key, &*human_content_safe)
+            .await;
+        chat_history_ref.set_memories(memories).await;
+
+        // eprintln!("\n==== CHAT LOG SO FAR ====");
+        // eprintln!("{}", guard.to_string(&*ai_name, &*start_context));
+        transport.typing(&medium).await;
+
+        if let Err(why) = self
+            .speak(transport, &medium, chat_history_ref, &*ai_name)
+            .await
+        {
+            eprintln!("Failed to get AI completions: {}", &why);
+
+            if let Err(why) = transport
+                .send(
+                    &medium,
+                    "Failed to complete, try resetting (check channel description to find out how)",
+                    &[],
+                )
+                .await
+            {
+                eprintln!("Failed to send AI error response message: {:?}", &why);
+            }
+        }
+    }
+
+    /// Runs whatever scheduled jobs are due, front-ends call this every so often
+    pub async fn run_due(&self, transport: &dyn Transport) {
+        for due in self
+            .scheduler
+            .take_due(chrono::Utc::now().timestamp())
+            .await
+        {
+            self.run_scheduled(transport, due).await;
+        }
+    }
+
+    pub async fn get_name(&self) -> String {
+        self.name
+            .read()
+            .await
+            .clone()
+            .unwrap_or_else(|| String::from("AI"))
+    }
+
+    /// Handles `!config show`, `!config unset <key>`, `!config guild <key> <value>` and
+    /// `!config guild unset <key>`, returning the text to reply with
+    async fn config_command(&self, medium: &ChatMedium, args: &str) -> String {
+        let guild_key = medium.guild_id();
+        let channel_key = medium.channel_id();
+        let args = args.trim();
+        let (subcommand, rest) = split_word(args);
+        match subcommand {
+            "" | "show" => {
+                let mut buf = String::from("```");
+                for explained in self.config_store.explain(guild_key, channel_key).await {
+                    let value = if explained.shown.chars().count() > 60 {
+                        format!("{}...", explained.shown.chars().take(60).collect::<String>())
+                    } else {
+                        explained.shown
+                    };
+                    buf.push_str(&*format!(
+                        "{} = {} ({})\n",
+                        explained.key,
+                        value,
+                        explained.source.to_string()
+                    ));
+                }
+                buf.push_str("```");
+                buf
+            }
+            "unset" => {
+                if self.config_store.unset_channel(channel_key, rest).await {
+                    format!("{} now inherits its value", rest)
+                } else {
+                    format!("{} has no channel override", rest)
+                }
+            }
+            "guild" => {
+                let guild_key = match guild_key {
+                    Some(guild_key) => guild_key,
+                    None => return String::from("Guild overrides can only be set in a guild"),
+                };
+                let (key, value) = split_word(rest);
+                if key == "unset" {
+                    if self.config_store.unset_guild(guild_key, value).await {
+                        format!("{} now inherits its value for this guild", value)
+                    } else {
+                        format!("{} has no guild override", value)
+                    }
+                } else {
+                    match self.config_store.set_guild(guild_key, key, value).await {
+                        Ok(confirmation) => format!("Guild default {}", confirmation),
+                        Err(why) => why,
+                    }
+                }
+            }
+            _ => String::from(
+                "Usage: !config show, !config unset <key>, !config guild <key> <value>, !config guild unset <key>",
+            ),
+        }
+    }
+
+    /// Picks the image attachments of `message` that fit the limits in `configuration`. Depending
+    /// on the image mode they're dropped, returned as urls for the chat model, or captioned and
+    /// appended to `line`.
+    async fn attach_images(
+        &self,
+        attachments: &[Attachment],
+        configuration: &Configuration,
+        mut line: String,
+    ) -> (String, Vec<String>) {
+        if configuration.images == vision::ImageMode::Off {
+            return (line, Vec::new());
+        }
+        let image_urls = attachments
+            .iter()
+            .filter(|attachment| {
+                attachment.is_image || vision::is_image_filename(&*attachment.filename)
+            })
+            .filter(|attachment| {
+                if attachment.size > configuration.max_image_bytes {
+                    eprintln!(
+                        "Skipping image {} of {} bytes, over the limit",
+                        &attachment.filename, attachment.size
+                    );
+                    false
+                } else {
+                    true
+                }
+            })
+            .take(configuration.max_images)
+            .map(|attachment| attachment.url.clone())
+            .collect::<Vec<_>>();
+        if configuration.images == vision::ImageMode::Native && configuration.chat_model.is_some() {
+            return (line, image_urls);
+        }
+        for url in image_urls {
+            match self.captioner.caption(&*url).await {
+                Ok(caption) => {
+                    line.push_str(&*format!(
+                        " [image: {}]",
+                        sanitize::flatten_lines(caption.trim())
+                    ));
+                }
+                Err(why) => {
+                    eprintln!("Failed to caption image: {}", &why);
+                    line.push_str(" [image]");
+                }
+            }
+        }
+        (line, Vec::new())
+    }
+
+    /// Draws every prompt that gets through moderation, prompts that fail are left out
+    async fn draw(&self, guild_id: Option<u64>, prompts: Vec<String>) -> Vec<draw::Drawing> {
+        let mut drawings = Vec::new();
+        for prompt in prompts {
+            match self
+                .moderator
+                .review(guild_id, moderation::Source::Input, &*prompt)
+                .await
+            {
+                moderation::Action::Allow => {}
+                _ => {
+                    eprintln!("Not drawing {:?}, it didn't pass moderation", &prompt);
+                    continue;
+                }
+            }
+            match self.artist.draw(&*prompt).await {
+                Ok(drawing) => drawings.push(drawing),
+                Err(why) => eprintln!("Failed to draw {:?}: {}", &prompt, &why),
+            }
+        }
+        drawings
+    }
+
+    /// Generates the next reply for the conversation and sends it, the last thing in the history
+    /// should be what's being replied to
+    async fn speak(
+        &self,
+        transport: &dyn Transport,
+        medium: &ChatMedium,
+        chat_history_ref: &mut ChatHistory,
+        ai_name: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let tool_context = tools::ToolContext::new(medium.guild_id());
+        let text = generate_response(
+            &self.gpt3_client,
+            &self.tools,
+            &tool_context,
+            chat_history_ref,
+            ai_name,
+        )
+        .await?;
+        let text = match self
+            .moderator
+            .review(medium.guild_id(), moderation::Source::Output, &*text)
+            .await
+        {
+            moderation::Action::Allow => text,
+            moderation::Action::Withhold => {
+                chat_history_ref.pop_last_exchange().await;
+                return Ok(());
+            }
+            moderation::Action::Warn(notice) => {
+                chat_history_ref.pop_last_exchange().await;
+                notice
+            }
+            moderation::Action::Replace(replacement) => {
+                chat_history_ref.replace_last_ai_log(&*replacement).await;
+                replacement
+            }
+        };
+        let mut asked_to_draw = false;
+        let (text, drawings) = if chat_history_ref.configuration.drawing {
+            let (text, mut prompts) = draw::extract_requests(&*text);
+            prompts.extend(tool_context.take_drawing_requests());
+            if prompts.is_empty() {
+                (text, Vec::new())
+            } else {
+                asked_to_draw = true;
+                let drawings = self.draw(medium.guild_id(), prompts).await;
+                // keep whatever else was logged for the reply, like tool calls
+                let (mut recorded, _) = draw::extract_requests(
+                    chat_history_ref
+                        .ai_chat_log
+                        .last()
+                        .map_or(&*text, |line| &**line),
+                );
+                for drawing in &drawings {
+                    recorded.push(' ');
+                    recorded.push_str(&*draw::describe(ai_name, &*drawing.prompt));
+                }
+                chat_history_ref.replace_last_ai_log(recorded.trim()).await;
+                (text, drawings)
+            }
+        } else {
+            (text, Vec::new())
+        };
+        let files = drawing_files(drawings);
+        if text.trim().is_empty() && files.is_empty() {
+            // nothing left to send, a reply that was only drawings that failed or nothing at all
+            chat_history_ref.pop_last_exchange().await;
+            if asked_to_draw {
+                self.reply(transport, medium, "Failed to draw that").await;
+            }
+            return Ok(());
+        }
+        if let Err(why) = transport.send(medium, &*text, &*files).await {
+            eprintln!("Failed to send AI completion response message: {:?}", &why);
+        } else {
+            for exchange in chat_history_ref.take_evicted_exchanges(&*ai_name) {
+                self.memory_store
+                    .remember(&*medium.key(), &*exchange, false)
+                    .await;
+            }
+            eprintln!("\n==== CHAT LOG SO FAR (WITH AI) ====");
+            eprintln!("{}", chat_history_ref.to_string(&*ai_name).await);
+            eprintln!("{} tokens so far", &chat_history_ref.tokens_so_far);
+        }
+        Ok(())
+    }
+
+    /// Archives the conversation if nobody has talked for longer than `inactivity_reset`, the old
+    /// exchanges and a summary of them go to long-term memory
+    async fn archive_if_inactive(&self, medium: &ChatMedium, chat_history_ref: &mut ChatHistory) {
+        let reset_after = match chat_history_ref.configuration.inactivity_reset {
+            Some(reset_after) => reset_after,
+            None => return,
+        };
+        match chat_history_ref.idle_secs(chrono::Utc::now().timestamp()) {
+            Some(idle_secs) if idle_secs >= reset_after => {}
+            _ => return,
+        }
+        let ai_name = self.get_name().await;
+        chat_history_ref.archive().await;
+        let exchanges = chat_history_ref.take_evicted_exchanges(&*ai_name);
+        match summarize_conversation(
+            &self.gpt3_client,
+            &chat_history_ref.configuration,
+            &*exchanges.join("\n"),
+        )
+        .await
+        {
+            Ok(summary) if !summary.trim().is_empty() => {
+                let summary = sanitize::flatten_lines(summary.trim());
+                self.memory_store
+                    .remember(
+                        &*medium.key(),
+                        &*format!("Summary of an earlier conversation: {}", summary),
+                        false,
+                    )
+                    .await;
+                chat_history_ref.set_summary(Some(summary)).await;
+            }
+            Ok(_) => {}
+            Err(why) => eprintln!("Failed to summarize archived conversation: {}", &why),
+        }
+        for exchange in exchanges {
+            self.memory_store
+                .remember(&*medium.key(), &*exchange, false)
+                .await;
+        }
+    }
+
+    /// Posts a scheduled job that came up, greetings and idle prompts are said in character
+    async fn run_scheduled(&self, transport: &dyn Transport, due: schedule::Due) {
+        let (medium, cue) = match due {
+            schedule::Due::Reminder(reminder) => {
+                let medium = ChatMedium::from_ids(reminder.guild_id, reminder.channel_id);
+                let text = format!(
+                    "{} Reminder: {}",
+                    transport.mention(&*reminder.user_id),
+                    reminder.text
+                );
+                if let Err(why) = transport.send(&medium, &*text, &[]).await {
+                    eprintln!("Failed to send reminder: {:?}", &why);
+                }
+                return;
+            }
+            schedule::Due::Greeting {
+                guild_id,
+                channel_id,
+            } => (
+                ChatMedium::from_ids(guild_id, channel_id),
+                String::from("(A new day has started. Greet everyone.)"),
+            ),
+            schedule::Due::Idle {
+                guild_id,
+                channel_id,
+                idle_secs,
+            } => (
+                ChatMedium::from_ids(guild_id, channel_id),
+                format!(
+                    "(Nobody has said anything for {}. Say something to get the conversation going again.)",
+                    schedule::describe_duration(idle_secs)
+                ),
+            ),
+        };
+        let configuration = self
+            .configuration(medium.guild_id(), medium.channel_id())
+            .await;
+        let ai_name = self.get_name().await;
+        let mut write_lock = self.history_map.history_map.write().await;
+        let chat_history_ref = write_lock.entry(medium).or_insert_with(|| {
+            ChatHistory::new(medium.guild_id().is_none(), configuration.clone())
+        });
+        chat_history_ref.set_configuration(configuration).await;
+        chat_history_ref.set_ai_name(&*ai_name).await;
+        chat_history_ref.set_pins(self.pinned(&medium).await).await;
+        self.archive_if_inactive(&medium, chat_history_ref).await;
+        chat_history_ref
+            .add_human_log("Narrator", &*cue, Vec::new(), None)
+            .await;
+        if let Err(why) = self
+            .speak(transport, &medium, chat_history_ref, &*ai_name)
+            .await
+        {
+            eprintln!("Failed to speak up on schedule: {}", &why);
+            chat_history_ref.pop_last_human_log().await;
+        }
+    }
+
+    async fn reply(&self, transport: &dyn Transport, medium: &ChatMedium, text: &str) {
+        if let Err(why) = transport.send(medium, text, &[]).await {
+            eprintln!("Failed to send message: {:?}", &why);
+        }
+    }
+}
+
+/// Splits off the first whitespace separated word of `text`, the same way `PermissionModel`
+/// reads subcommands
+fn split_word(text: &str) -> (&str, &str) {
+    match text.find(char::is_whitespace) {
+        Some(idx) => (&text[..idx], text[idx..].trim()),
+        None => (text, ""),
+    }
+}
+
+fn drawing_files(drawings: Vec<draw::Drawing>) -> Vec<OutgoingFile> {
+    drawings
+        .into_iter()
+        .map(|drawing| OutgoingFile {
+            filename: String::from("drawing.png"),
+            data: drawing.data,
+        })
+        .collect()
+}
+
+pub async fn generate_response(
+    gpt3_client: &api::GPT3Client,
+    tools: &tools::ToolRegistry,
+    tool_context: &tools::ToolContext,
+    chat_history_ref: &mut ChatHistory,
+    ai_name: &str,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    if let Some(chat_model) = chat_history_ref.configuration.chat_model.clone() {
+        return generate_chat_response(
+            gpt3_client,
+            tools,
+            tool_context,
+            chat_history_ref,
+            ai_name,
+            &*chat_model,
+        )
+        .await;
+    }
+    let mut response_buffer = String::new();
+    let mut first = true;
+    loop {
+        let prompt = if first {
+            chat_history_ref.to_prompt(ai_name).await
+        } else {
+            chat_history_ref.to_string(ai_name).await
+        };
+        let stop_tokens = chat_history_ref.get_stop_tokens(&*ai_name);
+        let params = chat_history_ref
+            .configuration
+            .completion_params(prompt.to_string(), stop_tokens);
+        params.validate()?;
+        let mut response = gpt3_client
+            .get_completion(types::Model::Davinci, params)
+            .await?;
+        if let Some(mut first_choice) = response.choices.pop() {
+            let choice_text = first_choice.text.replace("\n", " ");
+            let (choice_text, hit_speaker) =
+                chat_history_ref.truncate_at_speaker(&*choice_text, ai_name);
+            let choice_text = choice_text.to_string();
+            match (
+                first_choice.logprobs.take(),
+                &mut chat_history_ref.last_logprobs,
+            ) {
+                (Some(logprobs), Some(last_logprobs)) if !first => last_logprobs.extend(logprobs),
+                (logprobs, last_logprobs) => *last_logprobs = logprobs,
+            }
+            if first {
+                chat_history_ref.add_ai_log(&*choice_text).await;
+                first = false;
+            } else {
+                chat_history_ref.continue_last_ai_log(&*choice_text).await;
+            }
+            response_buffer.push_str(&*choice_text);
+            if hit_speaker || matches!(first_choice.finish_reason, types::FinishReason::Stop) {
+                break;
+            }
+        } else {
+            break;
+        }
+    }
+    Ok(response_buffer)
+}
+
+/// Like `generate_response`, but through a chat model. Chat models answer in one go, so there's
+/// no continuing a reply that ran out of tokens. When tools are on the model may call them
+/// first, their results are fed back until it answers, and the calls are logged in front of the
+/// reply in the transcript.
+async fn generate_chat_response(
+    gpt3_client: &api::GPT3Client,
+    tools: &tools::ToolRegistry,
+    tool_context: &tools::ToolContext,
+    chat_history_ref: &mut ChatHistory,
+    ai_name: &str,
+    chat_model: &str,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let mut messages = chat_history_ref.to_messages(ai_name);
+    let stop_tokens = chat_history_ref.get_stop_tokens(&*ai_name);
+    chat_history_ref.configuration.validate()?;
+    let tool_definitions = if chat_history_ref.configuration.tools {
+        let drawing = chat_history_ref.configuration.drawing;
+        tools
+            .definitions()
+            .into_iter()
+            .filter(|definition| drawing || definition.function.name != tools::DRAW_TOOL)
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let mut tool_log = Vec::new();
+    let mut text = String::new();
+    for round in 0..=tools::MAX_TOOL_ROUNDS {
+        let mut params = chat_history_ref.configuration.chat_params(
+            chat_model,
+            messages.clone(),
+            stop_tokens.clone(),
+        );
+        // out of rounds, the model has to answer with what it has
+        if round < tools::MAX_TOOL_ROUNDS {
+            params.tools = tool_definitions.clone();
+        }
+        let mut response = gpt3_client.get_chat_completion(params).await?;
+        let message = match response.choices.pop() {
+            Some(choice) => choice.message,
+            None => break,
+        };
+        if message.tool_calls.is_empty() {
+            text = message.content.unwrap_or_default();
+            break;
+        }
+        messages.push(types::ChatMessage::tool_request(
+            message.content.as_deref().unwrap_or(""),
+            message.tool_calls.clone(),
+        ));
+        for call in &message.tool_calls {
+            let result = tools.call(tool_context, call).await;
+            eprintln!("Tool call {:?} returned {:?}", &call.function, &result);
+            tool_log.push(tools::describe_call(call, &*result));
+            messages.push(types::ChatMessage::tool_result(&*call.id, &*result));
+        }
+    }
+    let text = sanitize::flatten_lines(&*text);
+    // chat models like to put their own name in front despite being told not to
+    let own_prefix = format!("{}:", ai_name);
+    let text = text.trim();
+    let text = text.strip_prefix(&*own_prefix).unwrap_or(text).trim();
+    let (text, _) = chat_history_ref.truncate_at_speaker(text, ai_name);
+    let text = text.to_string();
+    chat_history_ref.last_logprobs = None;
+    tool_log.push(text.clone());
+    chat_history_ref.add_ai_log(tool_log.join(" ").trim()).await;
+    Ok(text)
+}
+
+/// Sums up an archived conversation in a sentence or two, so it can be remembered as a whole
+async fn summarize_conversation(
+    gpt3_client: &api::GPT3Client,
+    configuration: &Configuration,
+    transcript: &str,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let prompt = format!(
+        "{}\n\nA summary of the conversation above in one or two sentences:",
+        transcript
+    );
+    match &configuration.chat_model {
+        Some(chat_model) => {
+            let mut params = configuration.chat_params(
+                chat_model,
+                vec![types::ChatMessage::new("user", &*prompt)],
+                Vec::new(),
+            );
+            params.max_tokens = 100;
+            params.stop_tokens = None;
+            let mut response = gpt3_client.get_chat_completion(params).await?;
+            Ok(response
+                .choices
+                .pop()
+                .and_then(|choice| choice.message.content)
+                .unwrap_or_default())
+        }
+        None => {
+            let mut params = configuration.completion_params(prompt, vec![String::from("\n\n")]);
+            params.max_tokens = 100;
+            params.best_of = None;
+            params.logprobs = None;
+            let mut response = gpt3_client
+                .get_completion(types::Model::Davinci, params)
+                .await?;
+            Ok(response
+                .choices
+                .pop()
+                .map(|choice| choice.text)
+                .unwrap_or_default())
+        }
+    }
+}
+
+/// Lists every token of `reply` with its probability and the most likely alternatives
+fn describe_logprobs(reply: &str, logprobs: &types::LogProbs) -> String {
+    use std::fmt::Write;
+    let mut buf = format!("```{}\n\n", reply.trim());
+    for (idx, token) in logprobs.tokens.iter().enumerate() {
+        let probability = logprobs
+            .token_logprobs
+            .get(idx)
+            .copied()
+            .flatten()
+            .map(|logprob| format!("{:6.2}%", logprob.exp() * 100.0))
+            .unwrap_or_else(|| String::from("     ?"));
+        let mut alternatives = logprobs
+            .top_logprobs
+            .get(idx)
+            .and_then(Option::as_ref)
+            .map(|top| {
+                top.iter()
+                    .filter(|(alternative, _)| *alternative != token)
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        alternatives
+            .sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal));
+        let alternatives = alternatives
+            .iter()
+            .map(|(alternative, logprob)| {
+                format!("{:?} {:.2}%", alternative, logprob.exp() * 100.0)
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        if let Err(why) = writeln!(buf, "{} {:?} {}", probability, token, alternatives) {
+            eprintln!("Failed to describe token probabilities: {:?}", &why);
+            break;
+        }
+        // discord messages are capped at 2000 characters
+        if buf.len() > 1900 {
+            buf.push_str("...\n");
+            break;
+        }
+    }
+    buf.push_str("```");
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{memory::HashingEmbedder, permissions::PermissionLevel, transport::SendResult};
+    use async_trait::async_trait;
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+    use warp::Filter;
+
+    const MEDIUM: ChatMedium = ChatMedium::Guild(1, 2);
+
+    /// Keeps the text of everything the engine sends
+    #[derive(Default)]
+    struct MockTransport {
+        sent: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl Transport for MockTransport {
+        async fn send(
+            &self,
+            _medium: &ChatMedium,
+            text: &str,
+            _files: &[OutgoingFile],
+        ) -> SendResult {
+            self.sent.lock().unwrap().push(text.trim().to_string());
+            Ok(())
+        }
+    }
+
+    impl MockTransport {
+        fn sent(&self) -> Vec<String> {
+            self.sent.lock().unwrap().clone()
+        }
+    }
+
+    /// None of the tests send images
+    struct NoCaptioner;
+
+    #[async_trait]
+    impl vision::Captioner for NoCaptioner {
+        async fn caption(
+            &self,
+            url: &str,
+        ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+            panic!("Nothing should be captioned, got {}", url)
+        }
+    }
+
+    /// A completions api on a local port that answers every prompt with `reply`, returns its
+    /// base url and the requests it got
+    fn stub_backend(reply: &'static str) -> (String, Arc<Mutex<Vec<Value>>>) {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        let completions = warp::post()
+            .and(warp::path!("engines" / String / "completions"))
+            .and(warp::body::json())
+            .map(move |_model: String, request: Value| {
+                seen.lock().unwrap().push(request);
+                warp::reply::json(&json!({
+                    "id": "stub",
+                    "object": "text_completion",
+                    "created": 0,
+                    "model": "davinci",
+                    "choices": [{
+                        "text": reply,
+                        "index": 0,
+                        "logprobs": null,
+                        "finish_reason": "stop",
+                    }],
+                }))
+            });
+        let (address, server) = warp::serve(completions).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (format!("http://{}", address), requests)
+    }
+
+    /// An engine talking to `base_url`, keeping its files in a fresh directory named after the
+    /// test
+    fn engine(test: &str, base_url: &str) -> Engine {
+        let dir = std::env::temp_dir().join(format!("dorothy-engine-test-{}", test));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |file: &str| dir.join(file).to_string_lossy().into_owned();
+        Engine::new(EngineParts {
+            gpt3_client: api::GPT3Client::with_base_url("test", base_url),
+            moderator: moderation::Moderator::new(
+                Vec::new(),
+                moderation::Policy::Block,
+                "[removed]",
+                &*path("moderation_policies.json"),
+            ),
+            permissions: PermissionModel::from_env(),
+            memory_store: memory::MemoryStore::new(
+                &*path("memory.json"),
+                Box::new(HashingEmbedder::new(64)),
+                3,
+                0.75,
+            ),
+            captioner: Box::new(NoCaptioner),
+            artist: draw::Artist::from_env("test"),
+            tools: tools::ToolRegistry::from_env(),
+            paths: StoragePaths {
+                config: path("config.json"),
+                profiles: path("profiles.json"),
+                schedule: path("schedule.json"),
+                personas: path("personas.json"),
+                templates: path("templates.json"),
+                pins: path("pins.json"),
+            },
+        })
+    }
+
+    fn message(text: &str) -> IncomingMessage {
+        IncomingMessage {
+            medium: MEDIUM,
+            is_private: false,
+            author_id: String::from("10"),
+            author_name: String::from("alice"),
+            disambiguator: String::from("0001"),
+            author_level: PermissionLevel::Everyone,
+            text: text.to_string(),
+            attachments: Vec::new(),
+            replying_to: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn replies_through_the_completions_api() {
+        let (base_url, requests) = stub_backend(" Hi alice!");
+        let engine = engine("replies", &*base_url);
+        engine.set_name("Dorothy").await;
+        let transport = MockTransport::default();
+        engine.handle(&transport, message("hello there")).await;
+        assert_eq!(transport.sent(), vec![String::from("Hi alice!")]);
+        let prompts = requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|request| request["prompt"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(prompts.len(), 1);
+        assert!(
+            prompts[0].ends_with("\n\nalice: hello there\nDorothy:"),
+            "{:?}",
+            prompts[0]
+        );
+        let conversation = engine.conversation(&MEDIUM).await.unwrap();
+        assert_eq!(conversation.transcript.human.len(), 1);
+        assert_eq!(conversation.transcript.ai, vec![String::from(" Hi alice!")]);
+    }
+
+    #[tokio::test]
+    async fn runs_commands_without_the_backend() {
+        let (base_url, requests) = stub_backend("unused");
+        let engine = engine("commands", &*base_url);
+        let transport = MockTransport::default();
+        engine.handle(&transport, message("!reset")).await;
+        assert_eq!(transport.sent(), vec![String::from("[Chatlog Cleared]")]);
+        assert!(requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn reports_a_backend_that_is_down() {
+        // nothing listens on the discard port
+        let engine = engine("down", "http://127.0.0.1:9");
+        let transport = MockTransport::default();
+        engine.handle(&transport, message("hello there")).await;
+        assert_eq!(
+            transport.sent(),
+            vec![String::from(
+                "Failed to complete, try resetting (check channel description to find out how)"
+            )]
+        );
+    }
+}
@@ -0,0 +1,25 @@
+//! The conversation engine behind the bot. Front-ends (Discord in `main.rs`) turn what they
+//! receive into `transport::IncomingMessage`s for `engine::Engine` and send its replies through
+//! their own `transport::Transport`.
+
+pub mod api;
+pub mod budget;
+pub mod configuration;
+pub mod draw;
+pub mod engine;
+pub mod history;
+pub mod memory;
+pub mod moderation;
+pub mod permissions;
+pub mod personas;
+pub mod pins;
+pub mod profiles;
+pub mod sanitize;
+pub mod schedule;
+pub mod server;
+pub mod storage;
+pub mod template;
+pub mod tools;
+pub mod transport;
+pub mod types;
+pub mod vision;
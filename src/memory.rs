@@ -0,0 +1,228 @@
+use crate::{api, storage, types};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::sync::RwLock;
+
+type EmbedResult = Result<Vec<f32>, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Turns text into a vector, texts about similar things should end up pointing the same way
+#[async_trait]
+pub trait Embedder: Send + Sync {
+    async fn embed(&self, text: &str) -> EmbedResult;
+}
+
+/// Embeds text through the OpenAI embeddings endpoint
+pub struct OpenAIEmbedder {
+    client: api::GPT3Client,
+    model: String,
+}
+
+impl OpenAIEmbedder {
+    pub fn new(token: &str, model: &str) -> Self {
+        OpenAIEmbedder {
+            client: api::GPT3Client::new(token),
+            model: model.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl Embedder for OpenAIEmbedder {
+    async fn embed(&self, text: &str) -> EmbedResult {
+        let mut embeddings = self
+            .client
+            .get_embeddings(types::EmbeddingRequestParams {
+                model: self.model.clone(),
+                input: text.to_string(),
+            })
+            .await?;
+        embeddings
+            .data
+            .pop()
+            .map(|embedding| embedding.embedding)
+            .ok_or_else(|| "Embeddings response had no data".into())
+    }
+}
+
+/// Embeds text locally by hashing its words into a fixed number of buckets. Much cruder than
+/// a real model, but it needs no network access and still matches memories sharing words.
+pub struct HashingEmbedder {
+    dimensions: usize,
+}
+
+impl HashingEmbedder {
+    pub fn new(dimensions: usize) -> Self {
+        HashingEmbedder { dimensions }
+    }
+
+    /// FNV-1a, used instead of the std hasher so stored embeddings stay valid across builds
+    fn hash(word: &str) -> u64 {
+        word.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        })
+    }
+}
+
+#[async_trait]
+impl Embedder for HashingEmbedder {
+    async fn embed(&self, text: &str) -> EmbedResult {
+        let mut vector = vec![0.0; self.dimensions];
+        for word in text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+        {
+            let bucket = HashingEmbedder::hash(&*word.to_lowercase()) as usize % self.dimensions;
+            vector[bucket] += 1.0;
+        }
+        Ok(vector)
+    }
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Memory {
+    pub text: String,
+    pub embedding: Vec<f32>,
+    /// Whether somebody asked for this to be remembered, rather than it falling out of the window
+    pub explicit: bool,
+}
+
+/// A vector index of memories per chat medium, persisted as json
+pub struct MemoryStore {
+    path: String,
+    embedder: Box<dyn Embedder>,
+    top_k: usize,
+    min_similarity: f32,
+    memories: RwLock<HashMap<String, Vec<Memory>>>,
+}
+
+impl MemoryStore {
+    /// Keeps memories in `path`, recalling at most `top_k` of them that are at least
+    /// `min_similarity` alike
+    pub fn new(path: &str, embedder: Box<dyn Embedder>, top_k: usize, min_similarity: f32) -> Self {
+        MemoryStore {
+            path: path.to_string(),
+            embedder,
+            top_k,
+            min_similarity,
+            memories: RwLock::new(storage::load_json(path)),
+        }
+    }
+
+    /// Reads `MEMORY_EMBEDDER` (`openai` or `local`), `MEMORY_EMBEDDING_MODEL`, `MEMORY_PATH`,
+    /// `MEMORY_TOP_K` and `MEMORY_MIN_SIMILARITY`
+    pub fn from_env(gpt3_token: &str) -> Self {
+        let embedder: Box<dyn Embedder> =
+            match &*std::env::var("MEMORY_EMBEDDER").unwrap_or_else(|_| String::from("openai")) {
+                "local" => Box::new(HashingEmbedder::new(256)),
+                _ => Box::new(OpenAIEmbedder::new(
+                    gpt3_token,
+                    &*std::env::var("MEMORY_EMBEDDING_MODEL")
+                        .unwrap_or_else(|_| String::from("text-embedding-ada-002")),
+                )),
+            };
+        MemoryStore::new(
+            &*std::env::var("MEMORY_PATH").unwrap_or_else(|_| String::from("memory.json")),
+            embedder,
+            std::env::var("MEMORY_TOP_K")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(3),
+            std::env::var("MEMORY_MIN_SIMILARITY")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(0.75),
+        )
+    }
+
+    async fn save(&self) {
+        storage::save_json(&self.path, &*self.memories.read().await);
+    }
+
+    pub async fn remember(&self, medium_key: &str, text: &str, explicit: bool) {
+        let embedding = match self.embedder.embed(text).await {
+            Ok(embedding) => embedding,
+            Err(why) => {
+                eprintln!("Failed to embed memory: {}", &why);
+                return;
+            }
+        };
+        self.memories
+            .write()
+            .await
+            .entry(medium_key.to_string())
+            .or_default()
+            .push(Memory {
+                text: text.to_string(),
+                embedding,
+                explicit,
+            });
+        self.save().await;
+    }
+
+    /// The `top_k` memories most similar to `query`, most similar first
+    pub async fn recall(&self, medium_key: &str, query: &str) -> Vec<String> {
+        // cloned so embedding the query doesn't hold up everyone else's memories
+        let memories = match self.memories.read().await.get(medium_key) {
+            Some(memories) if !memories.is_empty() => memories.clone(),
+            _ => return Vec::new(),
+        };
+        let query_embedding = match self.embedder.embed(query).await {
+            Ok(embedding) => embedding,
+            Err(why) => {
+                eprintln!("Failed to embed memory query: {}", &why);
+                return Vec::new();
+            }
+        };
+        let mut scored = memories
+            .iter()
+            .map(|memory| {
+                (
+                    cosine_similarity(&memory.embedding, &query_embedding),
+                    memory,
+                )
+            })
+            .filter(|(similarity, _)| *similarity >= self.min_similarity)
+            .collect::<Vec<_>>();
+        scored.sort_by(|(a, _), (b, _)| b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal));
+        scored
+            .into_iter()
+            .take(self.top_k)
+            .map(|(_, memory)| memory.text.clone())
+            .collect()
+    }
+
+    /// Forgets every memory containing `filter` (case insensitive), or all of them if it's empty.
+    /// Returns how many were forgotten.
+    pub async fn forget(&self, medium_key: &str, filter: &str) -> usize {
+        let filter = filter.trim().to_lowercase();
+        let forgotten = match self.memories.write().await.get_mut(medium_key) {
+            Some(memories) => {
+                let before = memories.len();
+                memories.retain(|memory| {
+                    !filter.is_empty() && !memory.text.to_lowercase().contains(&*filter)
+                });
+                before - memories.len()
+            }
+            None => 0,
+        };
+        if forgotten > 0 {
+            self.save().await;
+        }
+        forgotten
+    }
+}
@@ -0,0 +1,349 @@
+use crate::{api, storage, types};
+use async_trait::async_trait;
+use regex::{RegexSet, RegexSetBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::sync::RwLock;
+
+type CheckResult = Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>>;
+
+/// A `ModerationChecker` looks at a piece of text and returns the reasons it was flagged,
+/// an empty list means the text is fine
+#[async_trait]
+pub trait ModerationChecker: Send + Sync {
+    fn name(&self) -> &'static str;
+    async fn check(&self, text: &str) -> CheckResult;
+}
+
+/// Checks text against the OpenAI moderation endpoint
+pub struct OpenAIChecker {
+    client: api::GPT3Client,
+}
+
+impl OpenAIChecker {
+    pub fn new(token: &str) -> Self {
+        OpenAIChecker {
+            client: api::GPT3Client::new(token),
+        }
+    }
+}
+
+#[async_trait]
+impl ModerationChecker for OpenAIChecker {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
+
+    async fn check(&self, text: &str) -> CheckResult {
+        let moderation = self
+            .client
+            .get_moderation(types::ModerationRequestParams {
+                input: text.to_string(),
+            })
+            .await?;
+        Ok(moderation
+            .results
+            .into_iter()
+            .filter(|result| result.flagged)
+            .flat_map(|result| {
+                result
+                    .categories
+                    .into_iter()
+                    .filter(|(_, flagged)| *flagged)
+                    .map(|(category, _)| category)
+            })
+            .collect())
+    }
+}
+
+/// Checks text against a local list of case insensitive regular expressions
+pub struct KeywordChecker {
+    patterns: Vec<String>,
+    set: RegexSet,
+}
+
+impl KeywordChecker {
+    /// Reads one pattern per line, blank lines and lines starting with `#` are skipped
+    pub fn from_file(path: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let contents = std::fs::read_to_string(path)?;
+        let patterns = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(String::from)
+            .collect::<Vec<_>>();
+        let set = RegexSetBuilder::new(&patterns)
+            .case_insensitive(true)
+            .build()?;
+        Ok(KeywordChecker { patterns, set })
+    }
+}
+
+#[async_trait]
+impl ModerationChecker for KeywordChecker {
+    fn name(&self) -> &'static str {
+        "keywords"
+    }
+
+    async fn check(&self, text: &str) -> CheckResult {
+        Ok(self
+            .set
+            .matches(text)
+            .into_iter()
+            .map(|idx| self.patterns[idx].clone())
+            .collect())
+    }
+}
+
+/// What to do with flagged text in a guild
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Policy {
+    /// Withhold the text without telling anyone
+    Block,
+    /// Withhold the text and post a notice in its place
+    Warn,
+    /// Swap the text out for the replacement text and carry on
+    Replace,
+}
+
+impl Policy {
+    pub fn parse(value: &str) -> Option<Policy> {
+        match value.trim().to_lowercase().as_str() {
+            "block" => Some(Policy::Block),
+            "warn" => Some(Policy::Warn),
+            "replace" => Some(Policy::Replace),
+            _ => None,
+        }
+    }
+
+    pub fn to_string(&self) -> &'static str {
+        match self {
+            Policy::Block => "block",
+            Policy::Warn => "warn",
+            Policy::Replace => "replace",
+        }
+    }
+}
+
+/// Whether the text being reviewed came from a human or from the model
+#[derive(Clone, Copy, Debug)]
+pub enum Source {
+    Input,
+    Output,
+}
+
+impl Source {
+    fn to_string(&self) -> &'static str {
+        match self {
+            Source::Input => "input",
+            Source::Output => "output",
+        }
+    }
+}
+
+/// The result of reviewing a piece of text
+pub enum Action {
+    /// Nothing was flagged, use the text as-is
+    Allow,
+    /// Drop the text silently
+    Withhold,
+    /// Drop the text and post this notice instead
+    Warn(String),
+    /// Use this text in place of the original
+    Replace(String),
+}
+
+pub struct Moderator {
+    checkers: Vec<Box<dyn ModerationChecker>>,
+    default_policy: Policy,
+    policies_path: String,
+    guild_policies: RwLock<HashMap<u64, Policy>>,
+    replacement: String,
+}
+
+impl Moderator {
+    /// Runs `checkers` over everything, guild policies set with `set_policy` are kept in
+    /// `policies_path`
+    pub fn new(
+        checkers: Vec<Box<dyn ModerationChecker>>,
+        default_policy: Policy,
+        replacement: &str,
+        policies_path: &str,
+    ) -> Self {
+        Moderator {
+            checkers,
+            default_policy,
+            policies_path: policies_path.to_string(),
+            guild_policies: RwLock::new(storage::load_json(policies_path)),
+            replacement: replacement.to_string(),
+        }
+    }
+
+    /// Builds a moderator from `MODERATION_CHECKERS` (comma separated, `openai` and/or `keywords`),
+    /// `MODERATION_KEYWORDS_FILE`, `MODERATION_POLICY`, `MODERATION_REPLACEMENT` and
+    /// `MODERATION_POLICIES_PATH`, where the policies set per guild are kept
+    pub fn from_env(gpt3_token: &str) -> Self {
+        let mut checkers: Vec<Box<dyn ModerationChecker>> = Vec::new();
+        let checker_names = std::env::var("MODERATION_CHECKERS").unwrap_or_default();
+        for checker_name in checker_names.split(',').map(str::trim) {
+            match checker_name {
+                "" => {}
+                "openai" => checkers.push(Box::new(OpenAIChecker::new(gpt3_token))),
+                "keywords" => {
+                    let path = std::env::var("MODERATION_KEYWORDS_FILE")
+                        .unwrap_or_else(|_| String::from("moderation_keywords.txt"));
+                    match KeywordChecker::from_file(&*path) {
+                        Ok(checker) => checkers.push(Box::new(checker)),
+                        Err(why) => eprintln!("Failed to load keyword list {}: {}", &path, &why),
+                    }
+                }
+                unknown => eprintln!("Unknown moderation checker: {}", unknown),
+            }
+        }
+        Moderator::new(
+            checkers,
+            std::env::var("MODERATION_POLICY")
+                .ok()
+                .and_then(|value| Policy::parse(&*value))
+                .unwrap_or(Policy::Block),
+            &*std::env::var("MODERATION_REPLACEMENT").unwrap_or_else(|_| String::from("[removed]")),
+            &*std::env::var("MODERATION_POLICIES_PATH")
+                .unwrap_or_else(|_| String::from("moderation_policies.json")),
+        )
+    }
+
+    pub async fn policy(&self, guild_id: Option<u64>) -> Policy {
+        match guild_id {
+            Some(guild_id) => self
+                .guild_policies
+                .read()
+                .await
+                .get(&guild_id)
+                .copied()
+                .unwrap_or(self.default_policy),
+            None => self.default_policy,
+        }
+    }
+
+    pub async fn set_policy(&self, guild_id: u64, policy: Policy) {
+        let mut guild_policies = self.guild_policies.write().await;
+        guild_policies.insert(guild_id, policy);
+        storage::save_json(&self.policies_path, &*guild_policies);
+    }
+
+    /// Runs every checker over `text` and decides what to do with it based on the guild policy.
+    /// A checker that fails to respond is logged and treated as not flagging anything.
+    pub async fn review(&self, guild_id: Option<u64>, source: Source, text: &str) -> Action {
+        let mut reasons = Vec::new();
+        for checker in &self.checkers {
+            match checker.check(text).await {
+                Ok(checker_reasons) => reasons.extend(
+                    checker_reasons
+                        .into_iter()
+                        .map(|reason| format!("{}: {}", checker.name(), reason)),
+                ),
+                Err(why) => eprintln!(
+                    "Moderation checker {} failed on {}: {}",
+                    checker.name(),
+                    source.to_string(),
+                    &why
+                ),
+            }
+        }
+        if reasons.is_empty() {
+            return Action::Allow;
+        }
+        let policy = self.policy(guild_id).await;
+        eprintln!(
+            "Moderation flagged {} ({}), applying {} policy: {:?}\n{}",
+            source.to_string(),
+            reasons.join(", "),
+            policy.to_string(),
+            guild_id,
+            text
+        );
+        match policy {
+            Policy::Block => Action::Withhold,
+            Policy::Warn => Action::Warn(match source {
+                Source::Input => String::from("[Message withheld by moderation]"),
+                Source::Output => String::from("[Response withheld by moderation]"),
+            }),
+            Policy::Replace => Action::Replace(self.replacement.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Flags any text containing "bad"
+    struct StubChecker;
+
+    #[async_trait]
+    impl ModerationChecker for StubChecker {
+        fn name(&self) -> &'static str {
+            "stub"
+        }
+
+        async fn check(&self, text: &str) -> CheckResult {
+            Ok(if text.contains("bad") {
+                vec![String::from("bad")]
+            } else {
+                Vec::new()
+            })
+        }
+    }
+
+    fn moderator_with(test: &str, policy: Policy) -> (Moderator, String) {
+        let path = std::env::temp_dir()
+            .join(format!("dorothy-moderation-test-{}.json", test))
+            .to_string_lossy()
+            .into_owned();
+        std::fs::remove_file(&path).ok();
+        (
+            Moderator::new(vec![Box::new(StubChecker)], policy, "[removed]", &*path),
+            path,
+        )
+    }
+
+    #[tokio::test]
+    async fn allows_unflagged_text() {
+        let (moderator, _) = moderator_with("allow", Policy::Block);
+        let action = moderator.review(Some(1), Source::Input, "hello").await;
+        assert!(matches!(action, Action::Allow));
+    }
+
+    #[tokio::test]
+    async fn applies_each_policy_to_flagged_text() {
+        let (moderator, _) = moderator_with("block", Policy::Block);
+        let action = moderator.review(Some(1), Source::Input, "bad").await;
+        assert!(matches!(action, Action::Withhold));
+
+        let (moderator, _) = moderator_with("warn", Policy::Warn);
+        match moderator.review(Some(1), Source::Output, "bad").await {
+            Action::Warn(notice) => assert_eq!(notice, "[Response withheld by moderation]"),
+            _ => panic!("expected a warning"),
+        }
+
+        let (moderator, _) = moderator_with("replace", Policy::Replace);
+        match moderator.review(Some(1), Source::Output, "bad").await {
+            Action::Replace(replacement) => assert_eq!(replacement, "[removed]"),
+            _ => panic!("expected a replacement"),
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_guild_policies_across_restarts() {
+        let (moderator, path) = moderator_with("persist", Policy::Block);
+        moderator.set_policy(1, Policy::Replace).await;
+        assert_eq!(moderator.policy(Some(1)).await, Policy::Replace);
+        assert_eq!(moderator.policy(Some(2)).await, Policy::Block);
+        assert_eq!(moderator.policy(None).await, Policy::Block);
+
+        let restarted = Moderator::new(Vec::new(), Policy::Block, "[removed]", &*path);
+        assert_eq!(restarted.policy(Some(1)).await, Policy::Replace);
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,298 @@
+use std::collections::{HashMap, HashSet};
+use tokio::sync::RwLock;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PermissionLevel {
+    Everyone,
+    Moderator,
+    Admin,
+    Owner,
+}
+
+impl PermissionLevel {
+    pub fn parse(value: &str) -> Option<PermissionLevel> {
+        match value.trim().to_lowercase().as_str() {
+            "everyone" => Some(PermissionLevel::Everyone),
+            "mod" | "moderator" => Some(PermissionLevel::Moderator),
+            "admin" => Some(PermissionLevel::Admin),
+            "owner" => Some(PermissionLevel::Owner),
+            _ => None,
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            PermissionLevel::Everyone => "anyone",
+            PermissionLevel::Moderator => "a moderator",
+            PermissionLevel::Admin => "a server admin",
+            PermissionLevel::Owner => "a bot owner",
+        }
+    }
+}
+
+/// Extracts the command name from a `!` command, e.g. `context` from `!context=...`
+pub fn command_name(text: &str) -> &str {
+    let text = text.trim_start_matches('!');
+    let end = text
+        .find(|c: char| c.is_whitespace() || c == '=')
+        .unwrap_or_else(|| text.len());
+    &text[..end]
+}
+
+fn default_command_levels() -> HashMap<String, PermissionLevel> {
+    let mut levels = HashMap::new();
+    for command in &[
+        "reset",
+        "log",
+        "info",
+        "why",
+        "remember",
+        "pins",
+        "draw",
+        "remind",
+        "profile",
+        "config",
+        "config show",
+    ] {
+        levels.insert(command.to_string(), PermissionLevel::Everyone);
+    }
+    for command in &[
+        "temperature",
+        "top_p",
+        "frequency_penalty",
+        "presence_penalty",
+        "max_tokens",
+        "context_window",
+        "best_of",
+        "logprobs",
+        "logit_bias",
+        "stop",
+        "role_markers",
+        "chat_model",
+        "images",
+        "max_images",
+        "max_image_bytes",
+        "drawing",
+        "tools",
+        "greeting",
+        "idle",
+        "inactivity_reset",
+        "time_gaps",
+        "persona",
+        "template",
+        "context",
+        "forget",
+        "pin",
+        "unpin",
+        "config unset",
+    ] {
+        levels.insert(command.to_string(), PermissionLevel::Moderator);
+    }
+    for command in &["moderation", "config guild"] {
+        levels.insert(command.to_string(), PermissionLevel::Admin);
+    }
+    levels
+}
+
+fn env_list(key: &str, default: &str) -> Vec<String> {
+    std::env::var(key)
+        .unwrap_or_else(|_| default.to_string())
+        .split(',')
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+pub struct PermissionModel {
+    owners: RwLock<HashSet<String>>,
+    admin_roles: Vec<String>,
+    moderator_roles: Vec<String>,
+    command_levels: HashMap<String, PermissionLevel>,
+}
+
+impl PermissionModel {
+    /// Reads `BOT_OWNERS` (user ids), `ADMIN_ROLES` and `MODERATOR_ROLES` (role names) and
+    /// `COMMAND_PERMISSIONS` (e.g. `reset=moderator,context=admin`) to override the defaults
+    pub fn from_env() -> Self {
+        if std::env::var("BOT_OWNERS").is_err() {
+            eprintln!("BOT_OWNERS is not set, nobody is a bot owner");
+        }
+        let owners = env_list("BOT_OWNERS", "").into_iter().collect();
+        let mut command_levels = default_command_levels();
+        for entry in env_list("COMMAND_PERMISSIONS", "") {
+            let mut parts = entry.splitn(2, '=');
+            match (
+                parts.next(),
+                parts.next().and_then(|level| PermissionLevel::parse(level)),
+            ) {
+                (Some(command), Some(level)) => {
+                    command_levels.insert(command.trim_start_matches('!').to_string(), level);
+                }
+                _ => eprintln!("Invalid COMMAND_PERMISSIONS entry: {}", &entry),
+            }
+        }
+        PermissionModel {
+            owners: RwLock::new(owners),
+            admin_roles: env_list("ADMIN_ROLES", "Admin")
+                .into_iter()
+                .map(|role| role.to_lowercase())
+                .collect(),
+            moderator_roles: env_list("MODERATOR_ROLES", "Moderator,Mod")
+                .into_iter()
+                .map(|role| role.to_lowercase())
+                .collect(),
+            command_levels,
+        }
+    }
+
+    pub async fn add_owner(&self, user_id: &str) {
+        self.owners.write().await.insert(user_id.to_string());
+    }
+
+    /// The level needed to run `text`, matched against the longest run of its whitespace
+    /// separated words that is a command (so `config guild` can need more than `config`), or
+    /// `None` if there is no such command
+    pub fn required_level(&self, text: &str) -> Option<(&str, PermissionLevel)> {
+        let text = text.trim_start_matches('!');
+        let name = command_name(text);
+        let mut words = vec![name];
+        if !text[name.len()..].starts_with('=') {
+            words.extend(text[name.len()..].split_whitespace());
+        }
+        (1..=words.len())
+            .rev()
+            .find_map(|count| self.command_levels.get_key_value(&words[..count].join(" ")))
+            .map(|(command, level)| (command.as_str(), *level))
+    }
+
+    /// Works out the level of a user. Owners are always owners, everyone else gets the higher of
+    /// what the platform grants them (like Discord's `MANAGE_GUILD` making an admin) and what
+    /// their role names give them.
+    pub async fn level_for(
+        &self,
+        user_id: &str,
+        role_names: &[String],
+        granted: PermissionLevel,
+    ) -> PermissionLevel {
+        if self.owners.read().await.contains(user_id) {
+            return PermissionLevel::Owner;
+        }
+        let has_role = |roles: &[String]| {
+            role_names
+                .iter()
+                .any(|name| roles.contains(&name.to_lowercase()))
+        };
+        let from_roles = if has_role(&self.admin_roles) {
+            PermissionLevel::Admin
+        } else if has_role(&self.moderator_roles) {
+            PermissionLevel::Moderator
+        } else {
+            PermissionLevel::Everyone
+        };
+        granted.max(from_roles)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model() -> PermissionModel {
+        PermissionModel {
+            owners: RwLock::new(HashSet::new()),
+            admin_roles: vec![String::from("admin")],
+            moderator_roles: vec![String::from("moderator")],
+            command_levels: default_command_levels(),
+        }
+    }
+
+    #[test]
+    fn longest_command_wins() {
+        let model = model();
+        assert_eq!(
+            model.required_level("!config"),
+            Some(("config", PermissionLevel::Everyone))
+        );
+        assert_eq!(
+            model.required_level("!config show"),
+            Some(("config show", PermissionLevel::Everyone))
+        );
+        assert_eq!(
+            model.required_level("!config unset temperature"),
+            Some(("config unset", PermissionLevel::Moderator))
+        );
+        assert_eq!(
+            model.required_level("!config guild temperature 0.5"),
+            Some(("config guild", PermissionLevel::Admin))
+        );
+    }
+
+    #[test]
+    fn commands_end_at_whitespace_or_equals() {
+        let model = model();
+        assert_eq!(
+            model.required_level("!context=You are a pirate"),
+            Some(("context", PermissionLevel::Moderator))
+        );
+        assert_eq!(
+            model.required_level("!pin\tthe rules"),
+            Some(("pin", PermissionLevel::Moderator))
+        );
+        assert_eq!(
+            model.required_level("!pins"),
+            Some(("pins", PermissionLevel::Everyone))
+        );
+        assert_eq!(model.required_level("!pinnacle"), None);
+        assert_eq!(
+            model.required_level("!config guildx"),
+            Some(("config", PermissionLevel::Everyone))
+        );
+    }
+
+    #[test]
+    fn extra_whitespace_does_not_skip_a_subcommand() {
+        let model = model();
+        for text in &[
+            "!config  guild temperature 0.5",
+            "!config\tguild temperature 0.5",
+            "!config \t guild\ttemperature 0.5",
+        ] {
+            assert_eq!(
+                model.required_level(text),
+                Some(("config guild", PermissionLevel::Admin)),
+                "{:?}",
+                text
+            );
+        }
+        for text in &["!config  unset temperature", "!config\tunset\ttemperature"] {
+            assert_eq!(
+                model.required_level(text),
+                Some(("config unset", PermissionLevel::Moderator)),
+                "{:?}",
+                text
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn owners_and_roles_set_the_level() {
+        let model = model();
+        let roles = vec![String::from("Moderator")];
+        assert_eq!(
+            model
+                .level_for("1", &roles, PermissionLevel::Everyone)
+                .await,
+            PermissionLevel::Moderator
+        );
+        assert_eq!(
+            model.level_for("1", &[], PermissionLevel::Admin).await,
+            PermissionLevel::Admin
+        );
+        model.add_owner("1").await;
+        assert_eq!(
+            model.level_for("1", &[], PermissionLevel::Everyone).await,
+            PermissionLevel::Owner
+        );
+    }
+}
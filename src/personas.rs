@@ -0,0 +1,65 @@
+use crate::{configuration::Configuration, storage};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// An exchange showing how a persona talks, always in the prompt ahead of the conversation
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Example {
+    /// Who says `human`, "Human" if left out
+    #[serde(default)]
+    pub name: Option<String>,
+    pub human: String,
+    pub ai: String,
+}
+
+/// A character the bot can play, picked per channel or guild with the `persona` key
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct Persona {
+    /// Used in place of the configured context
+    pub context: String,
+    /// The template the persona talks through, unless one is configured
+    #[serde(default)]
+    pub template: Option<String>,
+    #[serde(default)]
+    pub examples: Vec<Example>,
+}
+
+/// Personas by name, read from a json file that is only ever edited by hand
+pub struct PersonaStore {
+    personas: BTreeMap<String, Persona>,
+}
+
+impl PersonaStore {
+    pub fn load(path: &str) -> Self {
+        PersonaStore {
+            personas: storage::load_json(path),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Persona> {
+        self.personas.get(name)
+    }
+
+    /// Every persona, ordered by name
+    pub fn list(&self) -> &BTreeMap<String, Persona> {
+        &self.personas
+    }
+
+    /// Swaps in the context, examples and template of the persona `configuration` asks for, if there is one
+    pub fn apply(&self, configuration: &mut Configuration) {
+        let name = match &configuration.persona {
+            Some(name) => name,
+            None => return,
+        };
+        match self.get(name) {
+            Some(persona) => {
+                configuration.context = persona.context.clone();
+                configuration.examples = persona.examples.clone();
+                if configuration.template.is_none() {
+                    configuration.template = persona.template.clone();
+                }
+            }
+            None => eprintln!("Unknown persona {}, using the context instead", name),
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use crate::storage;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::sync::RwLock;
+
+/// A fact that stays in the prompt of a channel no matter how long the conversation gets
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Pin {
+    pub text: String,
+    pub pinned_by: String,
+    /// Unix timestamp in seconds
+    pub at: i64,
+}
+
+/// Pins keyed by chat medium, oldest first, persisted as json
+pub struct PinStore {
+    path: String,
+    pins: RwLock<HashMap<String, Vec<Pin>>>,
+}
+
+impl PinStore {
+    pub fn load(path: &str) -> Self {
+        PinStore {
+            path: path.to_string(),
+            pins: RwLock::new(storage::load_json(path)),
+        }
+    }
+
+    async fn save(&self) {
+        storage::save_json(&self.path, &*self.pins.read().await);
+    }
+
+    pub async fn list(&self, medium_key: &str) -> Vec<Pin> {
+        self.pins
+            .read()
+            .await
+            .get(medium_key)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub async fn pin(&self, medium_key: &str, text: &str, pinned_by: &str) {
+        self.pins
+            .write()
+            .await
+            .entry(medium_key.to_string())
+            .or_default()
+            .push(Pin {
+                text: text.to_string(),
+                pinned_by: pinned_by.to_string(),
+                at: chrono::Utc::now().timestamp(),
+            });
+        self.save().await;
+    }
+
+    /// Removes the pin numbered `number` in `list`, counting from 1
+    pub async fn unpin(&self, medium_key: &str, number: usize) -> Option<Pin> {
+        let removed = match self.pins.write().await.get_mut(medium_key) {
+            Some(pins) if number >= 1 && number <= pins.len() => Some(pins.remove(number - 1)),
+            _ => None,
+        };
+        if removed.is_some() {
+            self.save().await;
+        }
+        removed
+    }
+}
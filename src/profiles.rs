@@ -0,0 +1,148 @@
+use crate::storage;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::sync::RwLock;
+
+/// What the bot knows about a single user, shared across every channel
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct Profile {
+    pub preferred_name: Option<String>,
+    pub pronouns: Option<String>,
+    pub facts: Vec<String>,
+    /// Opted out users have nothing stored about them and nothing injected into prompts
+    pub opted_out: bool,
+}
+
+impl Profile {
+    fn is_empty(&self) -> bool {
+        self.preferred_name.is_none() && self.pronouns.is_none() && self.facts.is_empty()
+    }
+
+    /// A line for the prompt describing the user, `None` if there's nothing to say
+    pub fn describe(&self, name: &str) -> Option<String> {
+        if self.opted_out || self.is_empty() {
+            return None;
+        }
+        let mut buf = String::from(name);
+        if let Some(pronouns) = &self.pronouns {
+            buf.push_str(&*format!(" ({})", pronouns));
+        }
+        buf.push(':');
+        if let Some(preferred_name) = &self.preferred_name {
+            buf.push_str(&*format!(" prefers to be called {}.", preferred_name));
+        }
+        for fact in &self.facts {
+            buf.push_str(&*format!(" {}.", fact.trim_end_matches('.')));
+        }
+        Some(buf)
+    }
+
+    /// Everything stored, for showing a user their own profile
+    pub fn summary(&self) -> String {
+        if self.opted_out {
+            return String::from("You have opted out, nothing is stored about you");
+        }
+        if self.is_empty() {
+            return String::from("Nothing is stored about you");
+        }
+        let mut buf = format!(
+            "Preferred name: {}\nPronouns: {}\nFacts:",
+            self.preferred_name.as_deref().unwrap_or("Not set"),
+            self.pronouns.as_deref().unwrap_or("Not set"),
+        );
+        for (idx, fact) in self.facts.iter().enumerate() {
+            buf.push_str(&*format!("\n{}. {}", idx + 1, fact));
+        }
+        buf
+    }
+}
+
+/// Profiles keyed by user id, persisted as json
+pub struct ProfileStore {
+    path: String,
+    profiles: RwLock<HashMap<String, Profile>>,
+}
+
+impl ProfileStore {
+    pub fn load(path: &str) -> Self {
+        ProfileStore {
+            path: path.to_string(),
+            profiles: RwLock::new(storage::load_json(path)),
+        }
+    }
+
+    async fn save(&self) {
+        storage::save_json(&self.path, &*self.profiles.read().await);
+    }
+
+    pub async fn get(&self, user_id: &str) -> Profile {
+        self.profiles
+            .read()
+            .await
+            .get(user_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Handles the text after `!profile`, returning the text to reply with
+    pub async fn command(&self, user_id: &str, args: &str) -> String {
+        let args = args.trim();
+        let (subcommand, value) = match args.find(' ') {
+            Some(idx) => (&args[..idx], args[idx..].trim()),
+            None => (args, ""),
+        };
+        let response = {
+            let mut write_lock = self.profiles.write().await;
+            let profile = write_lock.entry(user_id.to_string()).or_default();
+            match subcommand {
+                "" | "show" => return profile.summary(),
+                "optout" => {
+                    *profile = Profile {
+                        opted_out: true,
+                        ..Profile::default()
+                    };
+                    String::from("Opted out, everything stored about you has been deleted")
+                }
+                "optin" => {
+                    profile.opted_out = false;
+                    String::from("Opted back in")
+                }
+                "delete" => {
+                    let opted_out = profile.opted_out;
+                    *profile = Profile {
+                        opted_out,
+                        ..Profile::default()
+                    };
+                    String::from("Everything stored about you has been deleted")
+                }
+                _ if profile.opted_out => {
+                    return String::from("You have opted out, use \"!profile optin\" first")
+                }
+                "name" => {
+                    profile.preferred_name = Some(value.to_string()).filter(|v| !v.is_empty());
+                    String::from("Preferred name updated")
+                }
+                "pronouns" => {
+                    profile.pronouns = Some(value.to_string()).filter(|v| !v.is_empty());
+                    String::from("Pronouns updated")
+                }
+                "fact" if !value.is_empty() => {
+                    profile.facts.push(value.to_string());
+                    String::from("Fact saved")
+                }
+                "forget" => match value.parse::<usize>() {
+                    Ok(idx) if idx >= 1 && idx <= profile.facts.len() => {
+                        profile.facts.remove(idx - 1);
+                        String::from("Fact deleted")
+                    }
+                    _ => String::from("Usage: !profile forget <fact number>"),
+                },
+                _ => return String::from(
+                    "Usage: !profile [show], !profile name <name>, !profile pronouns <pronouns>, !profile fact <fact>, !profile forget <fact number>, !profile delete, !profile optout, !profile optin",
+                ),
+            }
+        };
+        self.save().await;
+        response
+    }
+}
@@ -0,0 +1,145 @@
+use serde::{Deserialize, Serialize};
+
+/// Role markers that are never legitimate at the start of a human line, on top of the names of
+/// the participants
+pub const GENERIC_ROLE_MARKERS: &[&str] = &["AI", "Human", "Assistant", "System", "User"];
+
+/// What to do with human lines that look like they're speaking as somebody else
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum RoleMarkerPolicy {
+    /// Defuse every "Name:" marker so it reads as "Name -"
+    Escape,
+    /// Drop lines that start with a marker, defuse the rest
+    Strip,
+    /// Leave markers alone, only line breaks are flattened
+    Off,
+}
+
+impl RoleMarkerPolicy {
+    pub fn parse(value: &str) -> Option<RoleMarkerPolicy> {
+        match value.trim().to_lowercase().as_str() {
+            "escape" => Some(RoleMarkerPolicy::Escape),
+            "strip" => Some(RoleMarkerPolicy::Strip),
+            "off" => Some(RoleMarkerPolicy::Off),
+            _ => None,
+        }
+    }
+
+    pub fn to_string(&self) -> &'static str {
+        match self {
+            RoleMarkerPolicy::Escape => "escape",
+            RoleMarkerPolicy::Strip => "strip",
+            RoleMarkerPolicy::Off => "off",
+        }
+    }
+}
+
+fn is_line_break(c: char) -> bool {
+    matches!(
+        c,
+        '\n' | '\r' | '\u{0b}' | '\u{0c}' | '\u{85}' | '\u{2028}' | '\u{2029}'
+    )
+}
+
+/// Swaps control characters for spaces and colon lookalikes for plain colons
+fn normalize_chars(line: &str) -> String {
+    line.chars()
+        .map(|c| match c {
+            '：' | '﹕' | '꞉' | '∶' => ':',
+            c if c.is_control() => ' ',
+            c => c,
+        })
+        .collect()
+}
+
+/// Flattens every kind of line break (not just `\n`) and control character into spaces
+pub fn flatten_lines(text: &str) -> String {
+    text.split(is_line_break)
+        .map(normalize_chars)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Strips anything from a display name that would break the "Name: line" format of the prompt
+/// or the stop sequences built from it, and caps its length
+pub fn sanitize_name(name: &str) -> String {
+    normalize_chars(name)
+        .chars()
+        .filter(|c| *c != ':')
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+        .take(32)
+        .collect()
+}
+
+fn is_markdown(c: char) -> bool {
+    matches!(c, '*' | '_' | '~' | '`' | '|')
+}
+
+/// Byte offsets of every colon in `line` that ends a role marker, like "Dorothy:", "ai :" or
+/// "**Dorothy**:"
+fn marker_colons(line: &str, markers: &[&str]) -> Vec<usize> {
+    line.match_indices(':')
+        .map(|(idx, _)| idx)
+        .filter(|idx| {
+            let before =
+                line[..*idx].trim_end_matches(|c: char| c.is_whitespace() || is_markdown(c));
+            markers.iter().any(|marker| {
+                if marker.is_empty() || before.len() < marker.len() {
+                    return false;
+                }
+                let start = before.len() - marker.len();
+                before.is_char_boundary(start)
+                    && before[start..].eq_ignore_ascii_case(marker)
+                    && before[..start]
+                        .chars()
+                        .next_back()
+                        .map_or(true, |c| !c.is_alphanumeric())
+            })
+        })
+        .collect()
+}
+
+/// Whether the line opens with a role marker, ignoring leading punctuation like `**` or `>`
+fn starts_with_marker(line: &str, markers: &[&str]) -> bool {
+    marker_colons(line, markers).first().map_or(false, |idx| {
+        let prefix = line[..*idx].trim_matches(|c: char| !c.is_alphanumeric());
+        markers
+            .iter()
+            .any(|marker| prefix.eq_ignore_ascii_case(marker))
+    })
+}
+
+fn escape_markers(line: &str, markers: &[&str]) -> String {
+    let mut buf = String::with_capacity(line.len());
+    let mut last = 0;
+    for idx in marker_colons(line, markers) {
+        buf.push_str(&line[last..idx]);
+        buf.push_str(" - ");
+        last = idx + 1;
+    }
+    buf.push_str(&line[last..]);
+    buf
+}
+
+/// Makes a human line safe to drop into a "Name: line" transcript. Line breaks are flattened so
+/// nobody can start a fresh transcript line, and depending on `policy` anything that looks like
+/// a role marker for one of `markers` is defused or dropped.
+pub fn sanitize_human_line(text: &str, markers: &[&str], policy: RoleMarkerPolicy) -> String {
+    text.split(is_line_break)
+        .map(normalize_chars)
+        .filter(|line| policy != RoleMarkerPolicy::Strip || !starts_with_marker(line, markers))
+        .map(|line| match policy {
+            RoleMarkerPolicy::Off => line,
+            _ => escape_markers(&*line, markers),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -0,0 +1,353 @@
+use crate::storage;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use tokio::sync::RwLock;
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+/// The longest duration `parse_duration` accepts
+const MAX_DURATION: i64 = 365 * SECONDS_PER_DAY;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Reminder {
+    pub guild_id: Option<u64>,
+    pub channel_id: u64,
+    pub user_id: String,
+    pub text: String,
+    /// Unix timestamp in seconds
+    pub due: i64,
+}
+
+/// A greeting posted once a day at a fixed time
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Greeting {
+    pub guild_id: Option<u64>,
+    /// Minutes after midnight UTC
+    pub minute_of_day: i64,
+    /// The day (counted from the unix epoch) the last greeting went out
+    pub last_day: Option<i64>,
+}
+
+/// Something said in character once a channel has been quiet for a while
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IdlePrompt {
+    pub guild_id: Option<u64>,
+    pub after_secs: i64,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct ScheduleFile {
+    reminders: Vec<Reminder>,
+    greetings: HashMap<u64, Greeting>,
+    idle_prompts: HashMap<u64, IdlePrompt>,
+}
+
+/// A scheduled job that has come up
+pub enum Due {
+    Reminder(Reminder),
+    Greeting {
+        guild_id: Option<u64>,
+        channel_id: u64,
+    },
+    Idle {
+        guild_id: Option<u64>,
+        channel_id: u64,
+        idle_secs: i64,
+    },
+}
+
+/// Reminders, daily greetings and idle prompts, persisted as json. When a channel was last
+/// active is only kept in memory, after a restart channels count as active since startup.
+pub struct Scheduler {
+    path: String,
+    started: i64,
+    file: RwLock<ScheduleFile>,
+    last_activity: RwLock<HashMap<u64, i64>>,
+    /// Channels that got their idle prompt and haven't been talked in since
+    idle_fired: RwLock<HashSet<u64>>,
+}
+
+/// Parses durations like "10m", "2h", "1h30m" or "45s" into seconds, up to a year
+pub fn parse_duration(text: &str) -> Option<i64> {
+    let mut total = 0;
+    let mut number = String::new();
+    for c in text.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => SECONDS_PER_DAY,
+            _ => return None,
+        };
+        total = number
+            .parse::<i64>()
+            .ok()?
+            .checked_mul(unit)
+            .and_then(|secs| secs.checked_add(total))?;
+        number.clear();
+    }
+    if !number.is_empty() || total <= 0 || total > MAX_DURATION {
+        return None;
+    }
+    Some(total)
+}
+
+pub fn describe_duration(secs: i64) -> String {
+    let parts = [
+        (secs / SECONDS_PER_DAY, "d"),
+        (secs % SECONDS_PER_DAY / 3600, "h"),
+        (secs % 3600 / 60, "m"),
+        (secs % 60, "s"),
+    ];
+    parts
+        .iter()
+        .filter(|(amount, _)| *amount > 0)
+        .map(|(amount, unit)| format!("{}{}", amount, unit))
+        .collect()
+}
+
+/// Describes a stretch of time in its largest unit, like "3 hours" or "2 days"
+pub fn describe_gap(secs: i64) -> String {
+    let (amount, unit) = if secs >= SECONDS_PER_DAY {
+        (secs / SECONDS_PER_DAY, "day")
+    } else if secs >= 3600 {
+        (secs / 3600, "hour")
+    } else {
+        ((secs / 60).max(1), "minute")
+    };
+    format!("{} {}{}", amount, unit, if amount == 1 { "" } else { "s" })
+}
+
+/// Parses "HH:MM" into minutes after midnight
+fn parse_time_of_day(text: &str) -> Option<i64> {
+    let mut parts = text.trim().splitn(2, ':');
+    let hours = parts.next()?.parse::<i64>().ok()?;
+    let minutes = parts.next()?.parse::<i64>().ok()?;
+    if (0..24).contains(&hours) && (0..60).contains(&minutes) {
+        Some(hours * 60 + minutes)
+    } else {
+        None
+    }
+}
+
+impl Scheduler {
+    pub fn load(path: &str) -> Self {
+        Scheduler {
+            path: path.to_string(),
+            started: chrono::Utc::now().timestamp(),
+            file: RwLock::new(storage::load_json(path)),
+            last_activity: RwLock::new(HashMap::new()),
+            idle_fired: RwLock::new(HashSet::new()),
+        }
+    }
+
+    async fn save(&self) {
+        storage::save_json(&self.path, &*self.file.read().await);
+    }
+
+    /// Records that somebody talked in the channel
+    pub async fn touch(&self, channel_id: u64) {
+        self.last_activity
+            .write()
+            .await
+            .insert(channel_id, chrono::Utc::now().timestamp());
+        self.idle_fired.write().await.remove(&channel_id);
+    }
+
+    /// Handles the text after `!remind`, like "me in 10m take out the trash"
+    pub async fn remind(
+        &self,
+        guild_id: Option<u64>,
+        channel_id: u64,
+        user_id: &str,
+        args: &str,
+    ) -> String {
+        let usage = String::from(
+            "Usage: !remind me in <duration like 10m, 2h or 1h30m, up to a year> <text>",
+        );
+        let args = args.trim();
+        let args = match args.strip_prefix("me in ") {
+            Some(args) => args.trim(),
+            None => return usage,
+        };
+        let (duration, text) = match args.find(' ') {
+            Some(idx) => (&args[..idx], args[idx..].trim()),
+            None => (args, ""),
+        };
+        let (secs, due) = match parse_duration(duration) {
+            Some(secs) if !text.is_empty() => {
+                match chrono::Utc::now().timestamp().checked_add(secs) {
+                    Some(due) => (secs, due),
+                    None => return usage,
+                }
+            }
+            _ => return usage,
+        };
+        self.file.write().await.reminders.push(Reminder {
+            guild_id,
+            channel_id,
+            user_id: user_id.to_string(),
+            text: text.to_string(),
+            due,
+        });
+        self.save().await;
+        format!("I'll remind you in {}", describe_duration(secs))
+    }
+
+    /// Handles the text after `!greeting`, a time like "09:00" (UTC) or "off"
+    pub async fn greeting_command(
+        &self,
+        guild_id: Option<u64>,
+        channel_id: u64,
+        args: &str,
+    ) -> String {
+        let response = match args.trim() {
+            "" => {
+                return match self.file.read().await.greetings.get(&channel_id) {
+                    Some(greeting) => format!(
+                        "Greeting every day at {:02}:{:02} UTC",
+                        greeting.minute_of_day / 60,
+                        greeting.minute_of_day % 60
+                    ),
+                    None => String::from("No daily greeting, set one with \"!greeting 09:00\""),
+                }
+            }
+            "off" => {
+                self.file.write().await.greetings.remove(&channel_id);
+                String::from("Daily greeting turned off")
+            }
+            time => match parse_time_of_day(time) {
+                Some(minute_of_day) => {
+                    // don't greet right away if today's greeting time has already passed
+                    let now = chrono::Utc::now().timestamp();
+                    let last_day = Some(now.div_euclid(SECONDS_PER_DAY))
+                        .filter(|_| minute_of_day <= now.rem_euclid(SECONDS_PER_DAY) / 60);
+                    self.file.write().await.greetings.insert(
+                        channel_id,
+                        Greeting {
+                            guild_id,
+                            minute_of_day,
+                            last_day,
+                        },
+                    );
+                    format!("Greeting every day at {} UTC", time)
+                }
+                None => return String::from("Usage: !greeting <HH:MM in UTC>, !greeting off"),
+            },
+        };
+        self.save().await;
+        response
+    }
+
+    /// Handles the text after `!idle`, a duration like "2h" or "off"
+    pub async fn idle_command(&self, guild_id: Option<u64>, channel_id: u64, args: &str) -> String {
+        let response = match args.trim() {
+            "" => {
+                return match self.file.read().await.idle_prompts.get(&channel_id) {
+                    Some(idle_prompt) => format!(
+                        "Speaking up after {} of silence",
+                        describe_duration(idle_prompt.after_secs)
+                    ),
+                    None => String::from("No idle prompt, set one with \"!idle 2h\""),
+                }
+            }
+            "off" => {
+                self.file.write().await.idle_prompts.remove(&channel_id);
+                String::from("Idle prompt turned off")
+            }
+            duration => match parse_duration(duration) {
+                Some(after_secs) => {
+                    self.file.write().await.idle_prompts.insert(
+                        channel_id,
+                        IdlePrompt {
+                            guild_id,
+                            after_secs,
+                        },
+                    );
+                    format!(
+                        "Speaking up after {} of silence",
+                        describe_duration(after_secs)
+                    )
+                }
+                None => return String::from("Usage: !idle <duration like 2h>, !idle off"),
+            },
+        };
+        self.save().await;
+        response
+    }
+
+    /// Everything that has come up by `now`, reminders are removed and greetings and idle
+    /// prompts are marked so they don't come up again right away
+    pub async fn take_due(&self, now: i64) -> Vec<Due> {
+        let mut due = Vec::new();
+        let mut changed = false;
+        {
+            let mut file = self.file.write().await;
+            let (ready, pending) = file
+                .reminders
+                .drain(..)
+                .partition::<Vec<_>, _>(|reminder| reminder.due <= now);
+            file.reminders = pending;
+            changed |= !ready.is_empty();
+            due.extend(ready.into_iter().map(Due::Reminder));
+
+            let today = now.div_euclid(SECONDS_PER_DAY);
+            let minute_of_day = now.rem_euclid(SECONDS_PER_DAY) / 60;
+            for (channel_id, greeting) in file.greetings.iter_mut() {
+                if greeting.minute_of_day <= minute_of_day && greeting.last_day != Some(today) {
+                    greeting.last_day = Some(today);
+                    changed = true;
+                    due.push(Due::Greeting {
+                        guild_id: greeting.guild_id,
+                        channel_id: *channel_id,
+                    });
+                }
+            }
+
+            let last_activity = self.last_activity.read().await;
+            let mut idle_fired = self.idle_fired.write().await;
+            for (channel_id, idle_prompt) in &file.idle_prompts {
+                let idle_secs = now
+                    - last_activity
+                        .get(channel_id)
+                        .copied()
+                        .unwrap_or(self.started);
+                if idle_secs >= idle_prompt.after_secs && idle_fired.insert(*channel_id) {
+                    due.push(Due::Idle {
+                        guild_id: idle_prompt.guild_id,
+                        channel_id: *channel_id,
+                        idle_secs,
+                    });
+                }
+            }
+        }
+        if changed {
+            self.save().await;
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("1h30m"), Some(5400));
+        assert_eq!(parse_duration("365d"), Some(MAX_DURATION));
+        assert_eq!(parse_duration("10"), None);
+        assert_eq!(parse_duration("0m"), None);
+    }
+
+    #[test]
+    fn refuses_durations_past_a_year() {
+        assert_eq!(parse_duration("366d"), None);
+        assert_eq!(parse_duration("99999999999999999d"), None);
+        assert_eq!(parse_duration("9223372036854775807s1s"), None);
+        assert_eq!(parse_duration("99999999999999999999s"), None);
+    }
+}
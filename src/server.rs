@@ -0,0 +1,568 @@
+use crate::{
+    engine::Engine,
+    history::ChatMedium,
+    permissions::PermissionLevel,
+    transport::{IncomingMessage, OutgoingFile, SendResult, Transport},
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{collections::HashMap, convert::Infallible, net::SocketAddr, sync::Arc, sync::Mutex};
+use warp::{http::StatusCode, reply::Response, Filter, Rejection, Reply};
+
+/// A single page that shows conversations through the api, asking for the admin token first
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+/// A reply the engine sent while handling a request
+#[derive(Serialize, Default)]
+struct SentReply {
+    text: String,
+    files: Vec<String>,
+}
+
+/// Keeps whatever the engine sends, so it can go back in the response
+#[derive(Default)]
+struct CollectingTransport {
+    replies: Mutex<Vec<SentReply>>,
+}
+
+#[async_trait]
+impl Transport for CollectingTransport {
+    async fn send(&self, _medium: &ChatMedium, text: &str, files: &[OutgoingFile]) -> SendResult {
+        let reply = SentReply {
+            text: text.to_string(),
+            files: files.iter().map(|file| file.filename.clone()).collect(),
+        };
+        self.replies
+            .lock()
+            .map_err(|_| "Reply list poisoned")?
+            .push(reply);
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct MediumQuery {
+    /// Leave it out for private conversations
+    guild_id: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct SendRequest {
+    author_id: String,
+    author_name: Option<String>,
+    text: String,
+    /// What the author may do with commands, `everyone` unless the caller vouches for more with
+    /// the admin token
+    level: Option<String>,
+}
+
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// A known token that isn't enough for the route
+#[derive(Debug)]
+struct Forbidden;
+
+impl warp::reject::Reject for Forbidden {}
+
+fn with_engine(
+    engine: Arc<Engine>,
+) -> impl Filter<Extract = (Arc<Engine>,), Error = Infallible> + Clone {
+    warp::any().map(move || engine.clone())
+}
+
+/// Lets requests through if they carry one of `tokens` as a bearer token, or anything at all if
+/// `is_open`. Requests carrying one of `lesser_tokens` instead are forbidden rather than
+/// unauthorized.
+fn authorized(
+    tokens: Vec<String>,
+    lesser_tokens: Vec<String>,
+    is_open: bool,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    let bearer = |tokens: Vec<String>| {
+        Arc::new(
+            tokens
+                .into_iter()
+                .map(|token| format!("Bearer {}", token))
+                .collect::<Vec<_>>(),
+        )
+    };
+    let expected = bearer(tokens);
+    let lesser = bearer(lesser_tokens);
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let expected = expected.clone();
+            let lesser = lesser.clone();
+            async move {
+                match header {
+                    _ if is_open => Ok(()),
+                    Some(header) if expected.contains(&header) => Ok(()),
+                    Some(header) if lesser.contains(&header) => {
+                        Err(warp::reject::custom(Forbidden))
+                    }
+                    _ => Err(warp::reject::custom(Unauthorized)),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// Whether the request carries `admin_token` as a bearer token
+fn is_admin(
+    admin_token: Option<String>,
+) -> impl Filter<Extract = (bool,), Error = Rejection> + Clone {
+    let expected = admin_token.map(|token| format!("Bearer {}", token));
+    warp::header::optional::<String>("authorization")
+        .map(move |header: Option<String>| expected.is_some() && header == expected)
+}
+
+/// `/api/conversations/<channel id>/...?guild_id=<guild id>`
+fn conversation() -> impl Filter<Extract = (ChatMedium,), Error = Rejection> + Clone {
+    warp::path!("api" / "conversations" / u64 / ..)
+        .and(warp::query::<MediumQuery>())
+        .map(|channel_id, query: MediumQuery| ChatMedium::from_ids(query.guild_id, channel_id))
+}
+
+fn error(status: StatusCode, message: &str) -> Response {
+    warp::reply::with_status(warp::reply::json(&json!({ "error": message })), status)
+        .into_response()
+}
+
+async fn list_conversations(engine: Arc<Engine>) -> Result<Response, Infallible> {
+    Ok(warp::reply::json(&engine.conversations().await).into_response())
+}
+
+async fn send_message(
+    medium: ChatMedium,
+    request: SendRequest,
+    is_admin: bool,
+    engine: Arc<Engine>,
+) -> Result<Response, Infallible> {
+    let author_level = match request.level.as_deref() {
+        None => PermissionLevel::Everyone,
+        Some(level) => match PermissionLevel::parse(level) {
+            Some(PermissionLevel::Everyone) => PermissionLevel::Everyone,
+            Some(_) if !is_admin => {
+                return Ok(error(
+                    StatusCode::FORBIDDEN,
+                    "Only the admin token can send with a raised level",
+                ))
+            }
+            Some(level) => level,
+            None => return Ok(error(StatusCode::BAD_REQUEST, "Unknown level")),
+        },
+    };
+    let transport = CollectingTransport::default();
+    let message = IncomingMessage {
+        medium,
+        is_private: medium.guild_id().is_none(),
+        author_name: request
+            .author_name
+            .unwrap_or_else(|| request.author_id.clone()),
+        author_id: request.author_id,
+        disambiguator: String::from("api"),
+        author_level,
+        text: request.text,
+        attachments: Vec::new(),
+        replying_to: None,
+    };
+    engine.handle(&transport, message).await;
+    let replies = transport.replies.into_inner().unwrap_or_default();
+    Ok(warp::reply::json(&json!({ "replies": replies })).into_response())
+}
+
+async fn get_history(medium: ChatMedium, engine: Arc<Engine>) -> Result<Response, Infallible> {
+    Ok(match engine.conversation(&medium).await {
+        Some(conversation) => warp::reply::json(&conversation).into_response(),
+        None => error(StatusCode::NOT_FOUND, "Nothing has been said there yet"),
+    })
+}
+
+async fn get_configuration(
+    medium: ChatMedium,
+    engine: Arc<Engine>,
+) -> Result<Response, Infallible> {
+    let keys = engine
+        .explain_configuration(&medium)
+        .await
+        .into_iter()
+        .map(|explained| {
+            json!({
+                "key": explained.key,
+                "value": explained.value,
+                "shown": explained.shown,
+                "source": explained.source.to_string(),
+            })
+        })
+        .collect::<Vec<_>>();
+    Ok(warp::reply::json(&keys).into_response())
+}
+
+/// Sets every key in the body, either to a value like the ones reading the configuration lists
+/// or to a string in the same format as the commands take them
+async fn put_configuration(
+    medium: ChatMedium,
+    values: HashMap<String, Value>,
+    engine: Arc<Engine>,
+) -> Result<Response, Infallible> {
+    let mut confirmations = Vec::new();
+    for (key, value) in values {
+        match engine.set_configuration(&medium, &*key, value).await {
+            Ok(confirmation) => confirmations.push(confirmation),
+            Err(why) => return Ok(error(StatusCode::BAD_REQUEST, &*why)),
+        }
+    }
+    Ok(warp::reply::json(&json!({ "confirmations": confirmations })).into_response())
+}
+
+async fn reset(medium: ChatMedium, engine: Arc<Engine>) -> Result<Response, Infallible> {
+    Ok(warp::reply::json(&json!({ "reset": engine.reset(&medium).await })).into_response())
+}
+
+async fn handle_rejection(rejection: Rejection) -> Result<Response, Infallible> {
+    Ok(if rejection.find::<Forbidden>().is_some() {
+        error(StatusCode::FORBIDDEN, "This needs the admin token")
+    } else if rejection.find::<Unauthorized>().is_some() {
+        error(StatusCode::UNAUTHORIZED, "Missing or wrong api token")
+    } else if rejection.is_not_found() {
+        error(StatusCode::NOT_FOUND, "Not found")
+    } else {
+        error(StatusCode::BAD_REQUEST, &*format!("{:?}", rejection))
+    })
+}
+
+/// Whether the api should be served at all, which it isn't without a token unless `is_open`
+fn should_serve(tokens: &[String], is_open: bool) -> bool {
+    if !tokens.is_empty() {
+        return true;
+    }
+    if is_open {
+        eprintln!("API_OPEN is set, the http api is open to anybody who can reach it");
+    } else {
+        eprintln!(
+            "Not serving the http api, set API_TOKEN or ADMIN_TOKEN (or API_OPEN=true to let \
+             anybody who can reach it in)"
+        );
+    }
+    is_open
+}
+
+/// Every route of the api and the dashboard, see `serve`
+fn routes(
+    engine: Arc<Engine>,
+    api_token: Option<String>,
+    admin_token: Option<String>,
+    is_open: bool,
+) -> impl Filter<Extract = impl Reply, Error = Infallible> + Clone {
+    let has_dashboard = admin_token.is_some();
+    let tokens = api_token
+        .iter()
+        .chain(admin_token.iter())
+        .cloned()
+        .collect::<Vec<_>>();
+    let dashboard = warp::path!("admin")
+        .and(warp::get())
+        .and_then(move || async move {
+            if has_dashboard {
+                Ok(warp::reply::html(DASHBOARD))
+            } else {
+                Err(warp::reject::not_found())
+            }
+        });
+    let api_auth = authorized(tokens, Vec::new(), is_open);
+    // everything the dashboard does on top, which shows and changes what others said
+    let admin_auth = authorized(
+        admin_token.clone().into_iter().collect(),
+        api_token.into_iter().collect(),
+        false,
+    );
+    let api = conversation()
+        .and(warp::path!("messages"))
+        .and(warp::post())
+        .and(api_auth.clone())
+        .and(warp::body::json())
+        .and(is_admin(admin_token))
+        .and(with_engine(engine.clone()))
+        .and_then(send_message)
+        .or(conversation()
+            .and(warp::path!("config"))
+            .and(warp::get())
+            .and(api_auth.clone())
+            .and(with_engine(engine.clone()))
+            .and_then(get_configuration))
+        .unify()
+        .or(warp::path!("api" / "personas")
+            .and(warp::get())
+            .and(api_auth)
+            .and(with_engine(engine.clone()))
+            .map(|engine: Arc<Engine>| warp::reply::json(engine.personas().list()).into_response()))
+        .unify();
+    let admin = warp::path!("api" / "conversations")
+        .and(warp::get())
+        .and(admin_auth.clone())
+        .and(with_engine(engine.clone()))
+        .and_then(list_conversations)
+        .or(conversation()
+            .and(warp::path!("history"))
+            .and(warp::get())
+            .and(admin_auth.clone())
+            .and(with_engine(engine.clone()))
+            .and_then(get_history))
+        .unify()
+        .or(conversation()
+            .and(warp::path!("config"))
+            .and(warp::put())
+            .and(admin_auth.clone())
+            .and(warp::body::json())
+            .and(with_engine(engine.clone()))
+            .and_then(put_configuration))
+        .unify()
+        .or(conversation()
+            .and(warp::path!("reset"))
+            .and(warp::post())
+            .and(admin_auth)
+            .and(with_engine(engine))
+            .and_then(reset))
+        .unify();
+    dashboard
+        .or(api.or(admin).unify())
+        .recover(handle_rejection)
+}
+
+/// Serves the http api on `address` until the process exits. Sending messages and reading the
+/// configuration and personas need `API_TOKEN` or `ADMIN_TOKEN` as a bearer token, listing
+/// conversations, reading transcripts, changing the configuration and resetting need
+/// `ADMIN_TOKEN`. Without either token the api isn't served at all, unless `API_OPEN` is `true`
+/// to let anybody who can reach `address` send messages. Messages can only be sent with a level
+/// above `everyone` using `ADMIN_TOKEN`. The admin dashboard is served on `/admin` when
+/// `ADMIN_TOKEN` is set.
+pub async fn serve(engine: Arc<Engine>, address: SocketAddr) {
+    let api_token = std::env::var("API_TOKEN").ok();
+    let admin_token = std::env::var("ADMIN_TOKEN").ok();
+    let is_open = std::env::var("API_OPEN").map_or(false, |value| value == "true");
+    let tokens = api_token
+        .iter()
+        .chain(admin_token.iter())
+        .cloned()
+        .collect::<Vec<_>>();
+    if !should_serve(&tokens, is_open) {
+        return;
+    }
+    println!("Serving the http api on {}", address);
+    warp::serve(routes(engine, api_token, admin_token, is_open))
+        .run(address)
+        .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api, draw,
+        engine::{EngineParts, StoragePaths},
+        memory::{self, HashingEmbedder},
+        moderation,
+        permissions::PermissionModel,
+        tools, vision,
+    };
+
+    const API_TOKEN: &str = "api-token";
+    const ADMIN_TOKEN: &str = "admin-token";
+
+    /// An engine whose backend is down, keeping its files in a fresh directory named after the
+    /// test
+    fn engine(test: &str) -> Arc<Engine> {
+        let dir = std::env::temp_dir().join(format!("dorothy-server-test-{}", test));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |file: &str| dir.join(file).to_string_lossy().into_owned();
+        // nothing listens on the discard port
+        let backend = "http://127.0.0.1:9";
+        Arc::new(Engine::new(EngineParts {
+            gpt3_client: api::GPT3Client::with_base_url("test", backend),
+            moderator: moderation::Moderator::new(
+                Vec::new(),
+                moderation::Policy::Block,
+                "[removed]",
+                &*path("moderation_policies.json"),
+            ),
+            permissions: PermissionModel::from_env(),
+            memory_store: memory::MemoryStore::new(
+                &*path("memory.json"),
+                Box::new(HashingEmbedder::new(64)),
+                3,
+                0.75,
+            ),
+            captioner: Box::new(vision::HttpCaptioner::new(backend)),
+            artist: draw::Artist::from_env("test"),
+            tools: tools::ToolRegistry::from_env(),
+            paths: StoragePaths {
+                config: path("config.json"),
+                profiles: path("profiles.json"),
+                schedule: path("schedule.json"),
+                personas: path("personas.json"),
+                templates: path("templates.json"),
+                pins: path("pins.json"),
+            },
+        }))
+    }
+
+    fn request(method: &str, path: &str, token: Option<&str>) -> warp::test::RequestBuilder {
+        let request = warp::test::request().method(method).path(path);
+        match token {
+            Some(token) => request.header("authorization", format!("Bearer {}", token)),
+            None => request,
+        }
+    }
+
+    #[tokio::test]
+    async fn needs_a_token() {
+        let routes = routes(
+            engine("no-token"),
+            Some(String::from(API_TOKEN)),
+            Some(String::from(ADMIN_TOKEN)),
+            false,
+        );
+        for (method, path) in &[
+            ("GET", "/api/conversations/2/config?guild_id=1"),
+            ("GET", "/api/personas"),
+            ("GET", "/api/conversations"),
+            ("POST", "/api/conversations/2/reset?guild_id=1"),
+        ] {
+            let response = request(method, path, None).reply(&routes).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{}", path);
+            let response = request(method, path, Some("wrong")).reply(&routes).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_admin_routes_from_the_api_token() {
+        let routes = routes(
+            engine("admin-routes"),
+            Some(String::from(API_TOKEN)),
+            Some(String::from(ADMIN_TOKEN)),
+            false,
+        );
+        let response = request(
+            "GET",
+            "/api/conversations/2/config?guild_id=1",
+            Some(API_TOKEN),
+        )
+        .reply(&routes)
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        for (method, path) in &[
+            ("GET", "/api/conversations"),
+            ("GET", "/api/conversations/2/history?guild_id=1"),
+            ("POST", "/api/conversations/2/reset?guild_id=1"),
+        ] {
+            let response = request(method, path, Some(API_TOKEN)).reply(&routes).await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{}", path);
+            let response = request(method, path, Some(ADMIN_TOKEN))
+                .reply(&routes)
+                .await;
+            let refused = [StatusCode::UNAUTHORIZED, StatusCode::FORBIDDEN];
+            assert!(!refused.contains(&response.status()), "{}", path);
+        }
+        let response = request(
+            "PUT",
+            "/api/conversations/2/config?guild_id=1",
+            Some(API_TOKEN),
+        )
+        .json(&json!({ "temperature": "0.5" }))
+        .reply(&routes)
+        .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn only_the_admin_token_raises_the_level() {
+        let routes = routes(
+            engine("levels"),
+            Some(String::from(API_TOKEN)),
+            Some(String::from(ADMIN_TOKEN)),
+            false,
+        );
+        let send = |token, level| {
+            request(
+                "POST",
+                "/api/conversations/2/messages?guild_id=1",
+                Some(token),
+            )
+            .json(&json!({ "author_id": "10", "text": "!reset", "level": level }))
+        };
+        let response = send(API_TOKEN, "admin").reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = send(API_TOKEN, "everyone").reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send(ADMIN_TOKEN, "admin").reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["replies"][0]["text"], "[Chatlog Cleared]");
+    }
+
+    #[tokio::test]
+    async fn open_api_needs_no_token_but_admin_routes_do() {
+        let routes = routes(engine("open"), None, None, true);
+        let response = request("GET", "/api/personas", None).reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = request("GET", "/api/conversations", None)
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    /// The value of every key in a configuration listing
+    fn values(body: &[u8]) -> serde_json::Map<String, Value> {
+        let keys: Vec<Value> = serde_json::from_slice(body).unwrap();
+        keys.into_iter()
+            .map(|key| {
+                (
+                    key["key"].as_str().unwrap().to_string(),
+                    key["value"].clone(),
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn configuration_survives_a_round_trip() {
+        let routes = routes(
+            engine("round-trip"),
+            None,
+            Some(String::from(ADMIN_TOKEN)),
+            false,
+        );
+        let path = "/api/conversations/2/config?guild_id=1";
+        let put = |values: &Value| request("PUT", path, Some(ADMIN_TOKEN)).json(values);
+        let response = put(&json!({
+            "chat_model": "gpt-3.5-turbo",
+            "stop": "###",
+            "inactivity_reset": "2h",
+            "logit_bias": "50256 -100",
+        }))
+        .reply(&routes)
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let read = || request("GET", path, Some(ADMIN_TOKEN));
+        let before = values(read().reply(&routes).await.body());
+        let response = put(&Value::Object(before.clone())).reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response.body());
+        assert_eq!(values(read().reply(&routes).await.body()), before);
+        assert_eq!(before["chat_model"], "gpt-3.5-turbo");
+        assert_eq!(before["stop"], json!(["###"]));
+        assert_eq!(before["persona"], Value::Null);
+        assert_eq!(before["inactivity_reset"], 7200);
+    }
+
+    #[test]
+    fn serves_without_a_token_only_when_open() {
+        assert!(!should_serve(&[], false));
+        assert!(should_serve(&[], true));
+        assert!(should_serve(&[String::from(API_TOKEN)], false));
+    }
+}
@@ -0,0 +1,28 @@
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Reads a json file, falling back to the default value (and logging why) if it is missing or
+/// can't be parsed
+pub fn load_json<T: DeserializeOwned + Default>(path: &str) -> T {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str(&*contents).unwrap_or_else(|why| {
+            eprintln!("Failed to parse {}: {}", path, &why);
+            T::default()
+        }),
+        Err(why) => {
+            eprintln!("Nothing loaded from {}: {}", path, &why);
+            T::default()
+        }
+    }
+}
+
+/// Writes `value` as pretty printed json, logging any failure
+pub fn save_json<T: Serialize>(path: &str, value: &T) {
+    match serde_json::to_string_pretty(value) {
+        Ok(contents) => {
+            if let Err(why) = std::fs::write(path, contents) {
+                eprintln!("Failed to write {}: {}", path, &why);
+            }
+        }
+        Err(why) => eprintln!("Failed to serialize {}: {}", path, &why),
+    }
+}
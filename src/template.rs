@@ -0,0 +1,126 @@
+use crate::{configuration::Configuration, storage};
+use handlebars::Handlebars;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Context, drawing instructions, pins, profiles, memories and the summary, available to every
+/// template as `{{> header}}`
+pub const DEFAULT_HEADER: &str = "{{context}}
+
+{{#if drawing}}{{drawing}}
+
+{{/if}}{{#if pins}}Keep in mind:
+{{#each pins}}- {{this}}
+{{/each}}
+{{/if}}{{#if profiles}}What {{ai_name}} knows about the people here:
+{{#each profiles}}- {{this}}
+{{/each}}
+{{/if}}{{#if memories}}Things {{ai_name}} remembers:
+{{#each memories}}- {{this}}
+{{/each}}
+{{/if}}{{#if summary}}What happened earlier: {{summary}}
+
+{{/if}}";
+
+/// What a transcript line replies to, available as `{{> reply}}` inside a line
+pub const DEFAULT_REPLY: &str =
+    "{{#if replying_to}}(replying to {{replying_to.name}}: {{replying_to.line}}) {{/if}}";
+
+pub const DEFAULT_PROMPT: &str = "{{> header}}{{#each examples}}{{speaker}}: {{text}}
+{{/each}}{{#each transcript}}{{#if gap}}{{gap}}
+{{/if}}{{speaker}}: {{> reply}}{{text}}{{#if @last}}{{#if is_ai}} {{else}}
+{{/if}}{{else}}
+{{/if}}{{/each}}{{#if respond}}{{ai_name}}:{{/if}}";
+
+pub const DEFAULT_SYSTEM: &str = "{{> header}}You are {{ai_name}}. Reply with only \
+{{ai_name}}'s next message, without a name in front of it.";
+
+pub const DEFAULT_LINE: &str = "{{#if gap}}{{gap}} {{/if}}{{speaker}}: {{> reply}}{{text}}";
+
+/// How prompts are written out, in handlebars. Every template sees `ai_name`, `persona`,
+/// `context`, `drawing`, `pins`, `profiles`, `memories`, `summary`, `participants`, `date`,
+/// `time` and `weekday`. `prompt` and `system` also see `transcript`, a list of lines with
+/// `speaker`, `text`, `is_ai`, `gap` and `replying_to` (with `name` and `line`), and
+/// `examples`, the persona's example exchanges as lines of the same kind. `line` sees a single
+/// one of those.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(default)]
+pub struct PromptTemplate {
+    /// The whole prompt for the completions api, `respond` is set when the model should write
+    /// the next line
+    pub prompt: String,
+    /// The system message for chat models
+    pub system: String,
+    /// Each human message for chat models
+    pub line: String,
+}
+
+impl std::default::Default for PromptTemplate {
+    fn default() -> Self {
+        PromptTemplate {
+            prompt: String::from(DEFAULT_PROMPT),
+            system: String::from(DEFAULT_SYSTEM),
+            line: String::from(DEFAULT_LINE),
+        }
+    }
+}
+
+/// Renders `template` with `data`, falling back to `fallback` if the template is broken so a
+/// typo in the templates file doesn't stop the bot from talking
+pub fn render(template: &str, fallback: &str, data: &Value) -> String {
+    let mut handlebars = Handlebars::new();
+    // prompts aren't html
+    handlebars.register_escape_fn(handlebars::no_escape);
+    for (name, partial) in &[("header", DEFAULT_HEADER), ("reply", DEFAULT_REPLY)] {
+        if let Err(why) = handlebars.register_partial(name, *partial) {
+            eprintln!("Failed to register the {} template: {:?}", name, &why);
+        }
+    }
+    match handlebars.render_template(template, data) {
+        Ok(rendered) => rendered,
+        Err(why) => {
+            eprintln!(
+                "Failed to render prompt template, using the default: {}",
+                &why
+            );
+            handlebars
+                .render_template(fallback, data)
+                .unwrap_or_default()
+        }
+    }
+}
+
+/// Templates by name, read from a json file that is only ever edited by hand
+pub struct TemplateStore {
+    templates: BTreeMap<String, PromptTemplate>,
+}
+
+impl TemplateStore {
+    pub fn load(path: &str) -> Self {
+        TemplateStore {
+            templates: storage::load_json(path),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&PromptTemplate> {
+        self.templates.get(name)
+    }
+
+    /// Every template, ordered by name
+    pub fn list(&self) -> &BTreeMap<String, PromptTemplate> {
+        &self.templates
+    }
+
+    /// Swaps in the template `configuration` asks for, if there is one
+    pub fn apply(&self, configuration: &mut Configuration) {
+        let name = match &configuration.template {
+            Some(name) => name,
+            None => return,
+        };
+        match self.get(name) {
+            Some(template) => configuration.prompt_template = template.clone(),
+            None => eprintln!("Unknown template {}, using the default", name),
+        }
+    }
+}
@@ -0,0 +1,526 @@
+use crate::{storage, types};
+use async_trait::async_trait;
+use rand::Rng;
+use regex::Regex;
+use serde_json::{json, Value};
+use std::{collections::HashMap, sync::Mutex};
+
+type ToolResult = Result<String, Box<dyn std::error::Error + Send + Sync>>;
+
+/// How many rounds of tool calls a single reply may take before the model has to answer
+pub const MAX_TOOL_ROUNDS: usize = 4;
+
+/// The name of the tool pictures are asked for through, only offered where drawing is on
+pub const DRAW_TOOL: &str = "draw";
+
+/// Where a tool is being called from
+pub struct ToolContext {
+    pub guild_id: Option<u64>,
+    /// Descriptions of the pictures asked for through the draw tool, sent along with the reply
+    pub drawing_requests: Mutex<Vec<String>>,
+}
+
+impl ToolContext {
+    pub fn new(guild_id: Option<u64>) -> Self {
+        ToolContext {
+            guild_id,
+            drawing_requests: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Hands over the pictures asked for so far
+    pub fn take_drawing_requests(&self) -> Vec<String> {
+        std::mem::take(
+            &mut *self
+                .drawing_requests
+                .lock()
+                .expect("Poisoned drawing requests"),
+        )
+    }
+}
+
+/// A Rust function the model can call, described to it by a json schema
+#[async_trait]
+pub trait Tool: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn description(&self) -> &'static str;
+    fn parameters(&self) -> Value;
+    async fn call(&self, context: &ToolContext, arguments: &Value) -> ToolResult;
+}
+
+pub fn string_argument<'a>(arguments: &'a Value, name: &str) -> Result<&'a str, String> {
+    arguments
+        .get(name)
+        .and_then(Value::as_str)
+        .ok_or_else(|| format!("Missing argument {}", name))
+}
+
+/// Rolls dice in the usual notation, like "2d6+1"
+pub struct DiceRoller;
+
+#[async_trait]
+impl Tool for DiceRoller {
+    fn name(&self) -> &'static str {
+        "roll_dice"
+    }
+
+    fn description(&self) -> &'static str {
+        "Rolls dice, like 1d20 or 3d6+2"
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "notation": {"type": "string", "description": "Dice notation, like 2d6+1"}
+            },
+            "required": ["notation"]
+        })
+    }
+
+    async fn call(&self, _context: &ToolContext, arguments: &Value) -> ToolResult {
+        let notation = string_argument(arguments, "notation")?.replace(' ', "");
+        let pattern = Regex::new(r"(?i)^(\d*)d(\d+)([+-]\d+)?$").expect("Invalid dice pattern");
+        let captures = pattern
+            .captures(&*notation)
+            .ok_or_else(|| format!("{} is not dice notation", notation))?;
+        let count: u32 = match captures.get(1).map(|count| count.as_str()) {
+            Some("") | None => 1,
+            Some(count) => count.parse()?,
+        };
+        let sides: u32 = captures[2].parse()?;
+        let modifier: i64 = match captures.get(3) {
+            Some(modifier) => modifier.as_str().parse()?,
+            None => 0,
+        };
+        if count == 0 || count > 100 || sides == 0 || sides > 1000 {
+            return Err("Between 1 and 100 dice with 1 to 1000 sides each".into());
+        }
+        let mut rng = rand::thread_rng();
+        let rolls = (0..count)
+            .map(|_| rng.gen_range(1, sides as i64 + 1))
+            .collect::<Vec<_>>();
+        let total = rolls.iter().sum::<i64>() + modifier;
+        Ok(format!(
+            "{} rolled {:?}{} for a total of {}",
+            notation,
+            rolls,
+            if modifier != 0 {
+                format!(" {:+}", modifier)
+            } else {
+                String::new()
+            },
+            total
+        ))
+    }
+}
+
+/// Tells the current date and time
+pub struct Clock;
+
+#[async_trait]
+impl Tool for Clock {
+    fn name(&self) -> &'static str {
+        "current_time"
+    }
+
+    fn description(&self) -> &'static str {
+        "Gets the current date and time, in UTC unless an offset is given"
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "utc_offset_hours": {"type": "number", "description": "Offset from UTC in hours, like -5 or 5.5"}
+            }
+        })
+    }
+
+    async fn call(&self, _context: &ToolContext, arguments: &Value) -> ToolResult {
+        let offset_hours = arguments
+            .get("utc_offset_hours")
+            .and_then(Value::as_f64)
+            .unwrap_or(0.0);
+        if offset_hours.abs() > 14.0 {
+            return Err("Offsets go from -14 to 14 hours".into());
+        }
+        let offset = chrono::FixedOffset::east((offset_hours * 3600.0) as i32);
+        let now = chrono::Utc::now().with_timezone(&offset);
+        Ok(now.format("%A %Y-%m-%d %H:%M (UTC%:z)").to_string())
+    }
+}
+
+/// Evaluates arithmetic with + - * / % ^ and parentheses
+pub struct Calculator;
+
+/// How deep parentheses, signs and powers may nest, so a long enough expression can't overflow
+/// the stack
+const MAX_EXPRESSION_DEPTH: usize = 64;
+
+struct ExpressionParser {
+    chars: Vec<char>,
+    pos: usize,
+    depth: usize,
+}
+
+impl ExpressionParser {
+    fn evaluate(expression: &str) -> Result<f64, String> {
+        let mut parser = ExpressionParser {
+            chars: expression.chars().filter(|c| !c.is_whitespace()).collect(),
+            pos: 0,
+            depth: 0,
+        };
+        let value = parser.expression()?;
+        match parser.peek() {
+            Some(c) => Err(format!("Unexpected {:?}", c)),
+            None => Ok(value),
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn expression(&mut self) -> Result<f64, String> {
+        let mut value = self.term()?;
+        loop {
+            match self.peek() {
+                Some('+') => {
+                    self.pos += 1;
+                    value += self.term()?;
+                }
+                Some('-') => {
+                    self.pos += 1;
+                    value -= self.term()?;
+                }
+                _ => return Ok(value),
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<f64, String> {
+        let mut value = self.unary()?;
+        loop {
+            match self.peek() {
+                Some('*') => {
+                    self.pos += 1;
+                    value *= self.unary()?;
+                }
+                Some('/') => {
+                    self.pos += 1;
+                    value /= self.unary()?;
+                }
+                Some('%') => {
+                    self.pos += 1;
+                    value %= self.unary()?;
+                }
+                _ => return Ok(value),
+            }
+        }
+    }
+
+    /// Every kind of nesting comes through here, so this is where the depth is kept track of
+    fn unary(&mut self) -> Result<f64, String> {
+        if self.depth >= MAX_EXPRESSION_DEPTH {
+            return Err(String::from("Expression too deeply nested"));
+        }
+        self.depth += 1;
+        let value = self.signed_power();
+        self.depth -= 1;
+        value
+    }
+
+    fn signed_power(&mut self) -> Result<f64, String> {
+        if self.peek() == Some('-') {
+            self.pos += 1;
+            return Ok(-self.unary()?);
+        }
+        let base = self.atom()?;
+        if self.peek() == Some('^') {
+            self.pos += 1;
+            return Ok(base.powf(self.unary()?));
+        }
+        Ok(base)
+    }
+
+    fn atom(&mut self) -> Result<f64, String> {
+        if self.peek() == Some('(') {
+            self.pos += 1;
+            let value = self.expression()?;
+            if self.peek() != Some(')') {
+                return Err(String::from("Missing closing parenthesis"));
+            }
+            self.pos += 1;
+            return Ok(value);
+        }
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_digit() || c == '.') {
+            self.pos += 1;
+        }
+        let number = self.chars[start..self.pos].iter().collect::<String>();
+        number.parse().map_err(|_| match self.peek() {
+            Some(c) if number.is_empty() => format!("Unexpected {:?}", c),
+            None if number.is_empty() => String::from("Unexpected end of expression"),
+            _ => format!("{} is not a number", number),
+        })
+    }
+}
+
+#[async_trait]
+impl Tool for Calculator {
+    fn name(&self) -> &'static str {
+        "calculate"
+    }
+
+    fn description(&self) -> &'static str {
+        "Evaluates an arithmetic expression with + - * / % ^ and parentheses"
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "expression": {"type": "string", "description": "Like (3 + 4) * 2 ^ 3"}
+            },
+            "required": ["expression"]
+        })
+    }
+
+    async fn call(&self, _context: &ToolContext, arguments: &Value) -> ToolResult {
+        let expression = string_argument(arguments, "expression")?;
+        Ok(format!(
+            "{} = {}",
+            expression,
+            ExpressionParser::evaluate(expression)?
+        ))
+    }
+}
+
+/// Asks for a picture to be drawn and sent with the reply, the drawing itself happens once the
+/// reply is written so it goes through the same moderation as `[draw: ...]`
+pub struct Painter;
+
+#[async_trait]
+impl Tool for Painter {
+    fn name(&self) -> &'static str {
+        DRAW_TOOL
+    }
+
+    fn description(&self) -> &'static str {
+        "Draws a picture and sends it along with your reply"
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "description": {"type": "string", "description": "What the picture shows, in detail"}
+            },
+            "required": ["description"]
+        })
+    }
+
+    async fn call(&self, context: &ToolContext, arguments: &Value) -> ToolResult {
+        let description = string_argument(arguments, "description")?.trim();
+        if description.is_empty() {
+            return Err("The description is empty".into());
+        }
+        context
+            .drawing_requests
+            .lock()
+            .expect("Poisoned drawing requests")
+            .push(description.to_string());
+        Ok(format!(
+            "A picture of {} will be sent with your reply",
+            description
+        ))
+    }
+}
+
+/// Answers from a json file of topics and what to know about them, no web access involved
+pub struct KnowledgeBase {
+    entries: HashMap<String, String>,
+}
+
+impl KnowledgeBase {
+    pub fn load(path: &str) -> Self {
+        KnowledgeBase {
+            entries: storage::load_json(path),
+        }
+    }
+}
+
+#[async_trait]
+impl Tool for KnowledgeBase {
+    fn name(&self) -> &'static str {
+        "search_knowledge"
+    }
+
+    fn description(&self) -> &'static str {
+        "Searches the knowledge base about this community for a topic"
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "query": {"type": "string", "description": "What to look up"}
+            },
+            "required": ["query"]
+        })
+    }
+
+    async fn call(&self, _context: &ToolContext, arguments: &Value) -> ToolResult {
+        let query = string_argument(arguments, "query")?.to_lowercase();
+        let words = query
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| word.len() > 2)
+            .collect::<Vec<_>>();
+        let mut scored = self
+            .entries
+            .iter()
+            .map(|(topic, text)| {
+                let haystack = format!("{} {}", topic, text).to_lowercase();
+                let score = words.iter().filter(|word| haystack.contains(*word)).count();
+                (score, topic, text)
+            })
+            .filter(|(score, _, _)| *score > 0)
+            .collect::<Vec<_>>();
+        scored.sort_by(|(a, _, _), (b, _, _)| b.cmp(a));
+        if scored.is_empty() {
+            return Ok(format!("Nothing is known about {}", query));
+        }
+        Ok(scored
+            .into_iter()
+            .take(3)
+            .map(|(_, topic, text)| format!("{}: {}", topic, text))
+            .collect::<Vec<_>>()
+            .join("\n"))
+    }
+}
+
+/// Every tool the bot offers, looked up by name when the model calls one
+pub struct ToolRegistry {
+    tools: Vec<Box<dyn Tool>>,
+}
+
+/// Whether `TOOLS`, a comma separated list out of dice, time, calculator, draw, user_lookup and
+/// knowledge, has `name` in it. Everything but user_lookup is on by default, looking people up
+/// tells the model about members who never talked to it so it has to be asked for.
+pub fn is_enabled(name: &str) -> bool {
+    std::env::var("TOOLS")
+        .unwrap_or_else(|_| String::from("dice,time,calculator,draw,knowledge"))
+        .split(',')
+        .any(|enabled| enabled.trim() == name)
+}
+
+impl ToolRegistry {
+    /// Registers the tools enabled in `TOOLS` that work anywhere, front-ends register their own
+    /// platform specific ones (like user_lookup) on top. Reads `KNOWLEDGE_PATH` for the
+    /// knowledge base.
+    pub fn from_env() -> Self {
+        let mut tools: Vec<Box<dyn Tool>> = Vec::new();
+        if is_enabled("dice") {
+            tools.push(Box::new(DiceRoller));
+        }
+        if is_enabled("time") {
+            tools.push(Box::new(Clock));
+        }
+        if is_enabled("calculator") {
+            tools.push(Box::new(Calculator));
+        }
+        if is_enabled("draw") {
+            tools.push(Box::new(Painter));
+        }
+        if is_enabled("knowledge") {
+            tools.push(Box::new(KnowledgeBase::load(
+                &*std::env::var("KNOWLEDGE_PATH")
+                    .unwrap_or_else(|_| String::from("knowledge.json")),
+            )));
+        }
+        ToolRegistry { tools }
+    }
+
+    pub fn register(&mut self, tool: Box<dyn Tool>) {
+        self.tools.push(tool);
+    }
+
+    pub fn definitions(&self) -> Vec<types::ToolDefinition> {
+        self.tools
+            .iter()
+            .map(|tool| types::ToolDefinition {
+                kind: String::from("function"),
+                function: types::FunctionDefinition {
+                    name: tool.name().to_string(),
+                    description: tool.description().to_string(),
+                    parameters: tool.parameters(),
+                },
+            })
+            .collect()
+    }
+
+    /// Runs the tool the model asked for, failures are reported back to the model as text so
+    /// it can try again or give up gracefully
+    pub async fn call(&self, context: &ToolContext, call: &types::ToolCall) -> String {
+        let tool = match self
+            .tools
+            .iter()
+            .find(|tool| tool.name() == call.function.name)
+        {
+            Some(tool) => tool,
+            None => return format!("Error: there is no tool called {}", call.function.name),
+        };
+        let arguments = match serde_json::from_str::<Value>(&*call.function.arguments) {
+            Ok(arguments) => arguments,
+            Err(why) => return format!("Error: the arguments aren't valid json: {}", why),
+        };
+        match tool.call(context, &arguments).await {
+            Ok(result) => result,
+            Err(why) => format!("Error: {}", why),
+        }
+    }
+}
+
+/// How a tool call shows up in the transcript
+pub fn describe_call(call: &types::ToolCall, result: &str) -> String {
+    let result = result.split_whitespace().collect::<Vec<_>>().join(" ");
+    let result = if result.chars().count() > 200 {
+        format!("{}...", result.chars().take(200).collect::<String>())
+    } else {
+        result
+    };
+    format!(
+        "[{} {} -> {}]",
+        call.function.name,
+        call.function
+            .arguments
+            .split_whitespace()
+            .collect::<String>(),
+        result
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evaluates_expressions() {
+        assert_eq!(ExpressionParser::evaluate("(3 + 4) * 2 ^ 3"), Ok(56.0));
+        assert_eq!(ExpressionParser::evaluate("-2 ^ 2"), Ok(-4.0));
+        assert_eq!(ExpressionParser::evaluate("10 % 4 - 1"), Ok(1.0));
+    }
+
+    #[test]
+    fn refuses_deep_nesting() {
+        let too_deep = Err(String::from("Expression too deeply nested"));
+        let parens = format!("{}1{}", "(".repeat(100_000), ")".repeat(100_000));
+        assert_eq!(ExpressionParser::evaluate(&*parens), too_deep);
+        assert_eq!(ExpressionParser::evaluate(&*"-".repeat(100_000)), too_deep);
+        let powers = format!("2{}", "^2".repeat(100_000));
+        assert_eq!(ExpressionParser::evaluate(&*powers), too_deep);
+        let fine = format!("{}1{}", "(".repeat(20), ")".repeat(20));
+        assert_eq!(ExpressionParser::evaluate(&*fine), Ok(1.0));
+    }
+}
@@ -0,0 +1,58 @@
+use crate::{history::ChatMedium, permissions::PermissionLevel};
+use async_trait::async_trait;
+
+pub type SendResult = Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+/// A file attached to an incoming message
+pub struct Attachment {
+    pub url: String,
+    pub filename: String,
+    pub size: u64,
+    /// Whether the platform says it's an image, the filename is checked on top of this
+    pub is_image: bool,
+}
+
+/// The message another one answers, as far as the platform tells
+pub struct ReferencedMessage {
+    pub author_name: String,
+    pub text: String,
+}
+
+/// A message from any front-end, with everything the engine needs to know about it
+pub struct IncomingMessage {
+    pub medium: ChatMedium,
+    pub is_private: bool,
+    /// Stays the same for an author across channels and renames, used to tell speakers apart and
+    /// to key their profile
+    pub author_id: String,
+    pub author_name: String,
+    /// Added to the author's name if somebody else in the conversation already goes by it
+    pub disambiguator: String,
+    /// Only has to be worked out for commands, front-ends may leave it at `Everyone` otherwise
+    pub author_level: PermissionLevel,
+    /// The text with line breaks kept and mentions resolved to names
+    pub text: String,
+    pub attachments: Vec<Attachment>,
+    /// The message this one replies to, for platforms with replies
+    pub replying_to: Option<ReferencedMessage>,
+}
+
+/// A file sent along with a reply
+pub struct OutgoingFile {
+    pub filename: String,
+    pub data: Vec<u8>,
+}
+
+/// How the engine talks back, every front-end implements this for its platform
+#[async_trait]
+pub trait Transport: Send + Sync {
+    async fn send(&self, medium: &ChatMedium, text: &str, files: &[OutgoingFile]) -> SendResult;
+
+    /// Shows that a reply is on its way, for platforms that have such a thing
+    async fn typing(&self, _medium: &ChatMedium) {}
+
+    /// How to address a user so they get notified
+    fn mention(&self, user_id: &str) -> String {
+        user_id.to_string()
+    }
+}
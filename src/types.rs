@@ -1,4 +1,16 @@
 use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, ops::RangeInclusive};
+
+pub const TEMPERATURE_RANGE: RangeInclusive<f64> = 0.0..=2.0;
+pub const TOP_P_RANGE: RangeInclusive<f64> = 0.0..=1.0;
+pub const PENALTY_RANGE: RangeInclusive<f64> = -2.0..=2.0;
+pub const MAX_TOKENS_RANGE: RangeInclusive<usize> = 1..=2048;
+pub const CONTEXT_WINDOW_RANGE: RangeInclusive<usize> = 256..=1_000_000;
+pub const BEST_OF_RANGE: RangeInclusive<usize> = 1..=20;
+pub const LOGIT_BIAS_RANGE: RangeInclusive<i32> = -100..=100;
+pub const LOGPROBS_RANGE: RangeInclusive<usize> = 0..=5;
+pub const MAX_STOP_SEQUENCES: usize = 4;
+pub const MAX_IMAGES_RANGE: RangeInclusive<usize> = 0..=10;
 
 #[derive(Serialize, Debug)]
 pub struct CompletionRequestParams {
@@ -9,13 +21,78 @@ pub struct CompletionRequestParams {
     pub presence_penalty: Option<f64>,
     pub frequency_penalty: Option<f64>,
 
-    pub top_p: Option<usize>,
+    pub top_p: Option<f64>,
 
     #[serde(rename = "n")]
     pub choices_per_prompt: Option<usize>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub best_of: Option<usize>,
+
+    /// Maps token ids to a bias between -100 and 100
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub logit_bias: HashMap<String, i32>,
+
     #[serde(rename = "stop")]
     pub stop_tokens: Option<Vec<String>>,
+
+    /// How many of the most likely alternatives to return for each token
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<usize>,
+}
+
+fn check_range<T: PartialOrd + std::fmt::Display>(
+    name: &str,
+    value: Option<T>,
+    range: &RangeInclusive<T>,
+) -> Result<(), String> {
+    match value {
+        Some(value) if !range.contains(&value) => Err(format!(
+            "{} must be between {} and {} (got {})",
+            name,
+            range.start(),
+            range.end(),
+            value
+        )),
+        _ => Ok(()),
+    }
+}
+
+impl CompletionRequestParams {
+    /// Checks every parameter against the ranges the completions api accepts
+    pub fn validate(&self) -> Result<(), String> {
+        check_range("temperature", self.temperature, &TEMPERATURE_RANGE)?;
+        check_range("top_p", self.top_p, &TOP_P_RANGE)?;
+        check_range("presence_penalty", self.presence_penalty, &PENALTY_RANGE)?;
+        check_range("frequency_penalty", self.frequency_penalty, &PENALTY_RANGE)?;
+        check_range("max_tokens", Some(self.max_tokens), &MAX_TOKENS_RANGE)?;
+        check_range("best_of", self.best_of, &BEST_OF_RANGE)?;
+        check_range("logprobs", self.logprobs, &LOGPROBS_RANGE)?;
+        if let (Some(best_of), Some(choices)) = (self.best_of, self.choices_per_prompt) {
+            if best_of < choices {
+                return Err(format!(
+                    "best_of ({}) must be at least n ({})",
+                    best_of, choices
+                ));
+            }
+        }
+        for (token, bias) in &self.logit_bias {
+            if token.parse::<u32>().is_err() {
+                return Err(format!("logit_bias token {} is not a token id", token));
+            }
+            check_range("logit_bias", Some(*bias), &LOGIT_BIAS_RANGE)?;
+        }
+        if let Some(stop_tokens) = &self.stop_tokens {
+            if stop_tokens.len() > MAX_STOP_SEQUENCES {
+                return Err(format!(
+                    "at most {} stop sequences are allowed (got {})",
+                    MAX_STOP_SEQUENCES,
+                    stop_tokens.len()
+                ));
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -30,6 +107,10 @@ pub enum FinishReason {
     Length,
     #[serde(rename = "stop")]
     Stop,
+    #[serde(rename = "content_filter")]
+    ContentFilter,
+    #[serde(rename = "tool_calls")]
+    ToolCalls,
 }
 
 impl std::default::Default for FinishReason {
@@ -44,11 +125,31 @@ impl std::default::Default for Object {
     }
 }
 
+/// Per token log probabilities, only present when `logprobs` was requested.
+/// The first token of a completion can have a `null` log probability.
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct LogProbs {
+    pub tokens: Vec<String>,
+    pub token_logprobs: Vec<Option<f64>>,
+    pub top_logprobs: Vec<Option<HashMap<String, f64>>>,
+    pub text_offset: Vec<usize>,
+}
+
+impl LogProbs {
+    /// Appends the tokens of a continuation completion
+    pub fn extend(&mut self, other: LogProbs) {
+        self.tokens.extend(other.tokens);
+        self.token_logprobs.extend(other.token_logprobs);
+        self.top_logprobs.extend(other.top_logprobs);
+        self.text_offset.extend(other.text_offset);
+    }
+}
+
 #[derive(Deserialize, Debug, Default)]
 pub struct Choice {
     pub text: String,
     index: usize,
-    log_probability: Option<f64>, // an assumption
+    pub logprobs: Option<LogProbs>,
     pub finish_reason: FinishReason,
 }
 
@@ -65,6 +166,205 @@ pub struct Completion {
     pub choices: Vec<Choice>,
 }
 
+#[derive(Serialize, Debug, Clone)]
+pub struct ImageUrl {
+    pub url: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+/// Either plain text or a list of parts, which is how images get sent to vision models
+#[derive(Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ChatContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FunctionCall {
+    pub name: String,
+    /// The arguments as a json string, which the model doesn't always get right
+    pub arguments: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct FunctionDefinition {
+    pub name: String,
+    pub description: String,
+    /// A json schema describing the arguments
+    pub parameters: serde_json::Value,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ToolDefinition {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: FunctionDefinition,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: ChatContent,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+impl ChatMessage {
+    pub fn new(role: &str, text: &str) -> Self {
+        ChatMessage::with_content(role, ChatContent::Text(text.to_string()))
+    }
+
+    pub fn with_content(role: &str, content: ChatContent) -> Self {
+        ChatMessage {
+            role: role.to_string(),
+            content,
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        }
+    }
+
+    /// The assistant message asking for tools to be called, which has to be sent back along
+    /// with their results
+    pub fn tool_request(text: &str, tool_calls: Vec<ToolCall>) -> Self {
+        ChatMessage {
+            tool_calls,
+            ..ChatMessage::new("assistant", text)
+        }
+    }
+
+    pub fn tool_result(tool_call_id: &str, result: &str) -> Self {
+        ChatMessage {
+            tool_call_id: Some(tool_call_id.to_string()),
+            ..ChatMessage::new("tool", result)
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ChatCompletionRequestParams {
+    pub model: String,
+    pub messages: Vec<ChatMessage>,
+    pub max_tokens: usize,
+    pub temperature: Option<f64>,
+
+    pub presence_penalty: Option<f64>,
+    pub frequency_penalty: Option<f64>,
+
+    pub top_p: Option<f64>,
+
+    #[serde(rename = "n")]
+    pub choices_per_prompt: Option<usize>,
+
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub logit_bias: HashMap<String, i32>,
+
+    #[serde(rename = "stop")]
+    pub stop_tokens: Option<Vec<String>>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<ToolDefinition>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct ChatResponseMessage {
+    role: String,
+    pub content: Option<String>,
+    #[serde(default)]
+    pub tool_calls: Vec<ToolCall>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct ChatChoice {
+    index: usize,
+    pub message: ChatResponseMessage,
+    pub finish_reason: FinishReason,
+}
+
+/// `ChatCompletion` is the response object from a messages-style chat completion api call
+#[derive(Deserialize, Debug, Default)]
+pub struct ChatCompletion {
+    id: Option<String>,
+    model: String,
+    pub choices: Vec<ChatChoice>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ImageGenerationRequestParams {
+    pub model: String,
+    pub prompt: String,
+    pub n: usize,
+    pub size: String,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct GeneratedImage {
+    pub url: Option<String>,
+    pub revised_prompt: Option<String>,
+}
+
+/// `ImageGeneration` is the response object from an OpenAI image generation api call
+#[derive(Deserialize, Debug, Default)]
+pub struct ImageGeneration {
+    created: u64,
+    pub data: Vec<GeneratedImage>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ModerationRequestParams {
+    pub input: String,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct ModerationResult {
+    pub flagged: bool,
+    pub categories: HashMap<String, bool>,
+    pub category_scores: HashMap<String, f64>,
+}
+
+/// `Moderation` is the response object from an OpenAI moderation api call
+#[derive(Deserialize, Debug, Default)]
+pub struct Moderation {
+    id: Option<String>,
+    model: String,
+    pub results: Vec<ModerationResult>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct EmbeddingRequestParams {
+    pub model: String,
+    pub input: String,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct Embedding {
+    pub embedding: Vec<f32>,
+    index: usize,
+}
+
+/// `Embeddings` is the response object from an OpenAI embeddings api call
+#[derive(Deserialize, Debug, Default)]
+pub struct Embeddings {
+    pub data: Vec<Embedding>,
+    model: String,
+}
+
 /// Spectrum
 pub enum Model {
     /// Most capable
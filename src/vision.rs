@@ -0,0 +1,155 @@
+use crate::{api, types};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+type CaptionResult = Result<String, Box<dyn std::error::Error + Send + Sync>>;
+
+/// How image attachments make their way into the conversation
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageMode {
+    /// Images are ignored
+    Off,
+    /// Images are described by the captioning backend and added to the line as "[image: ...]"
+    Caption,
+    /// Images are passed to the chat model as image parts, needs `chat_model` to be set to a
+    /// vision capable model, otherwise this falls back to captions
+    Native,
+}
+
+impl ImageMode {
+    pub fn parse(value: &str) -> Option<ImageMode> {
+        match value.trim().to_lowercase().as_str() {
+            "off" => Some(ImageMode::Off),
+            "caption" => Some(ImageMode::Caption),
+            "native" => Some(ImageMode::Native),
+            _ => None,
+        }
+    }
+
+    pub fn to_string(&self) -> &'static str {
+        match self {
+            ImageMode::Off => "off",
+            ImageMode::Caption => "caption",
+            ImageMode::Native => "native",
+        }
+    }
+}
+
+/// Describes the image at a url in a short sentence
+#[async_trait]
+pub trait Captioner: Send + Sync {
+    async fn caption(&self, url: &str) -> CaptionResult;
+}
+
+/// Captions images by asking a vision capable OpenAI chat model to describe them
+pub struct OpenAICaptioner {
+    client: api::GPT3Client,
+    model: String,
+}
+
+impl OpenAICaptioner {
+    pub fn new(token: &str, model: &str) -> Self {
+        OpenAICaptioner {
+            client: api::GPT3Client::new(token),
+            model: model.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl Captioner for OpenAICaptioner {
+    async fn caption(&self, url: &str) -> CaptionResult {
+        let mut completion = self
+            .client
+            .get_chat_completion(types::ChatCompletionRequestParams {
+                model: self.model.clone(),
+                messages: vec![types::ChatMessage::with_content(
+                    "user",
+                    types::ChatContent::Parts(vec![
+                        types::ContentPart::Text {
+                            text: String::from("Describe this image in one short sentence."),
+                        },
+                        types::ContentPart::ImageUrl {
+                            image_url: types::ImageUrl {
+                                url: url.to_string(),
+                            },
+                        },
+                    ]),
+                )],
+                max_tokens: 60,
+                temperature: Some(0.2),
+                presence_penalty: None,
+                frequency_penalty: None,
+                top_p: None,
+                choices_per_prompt: Some(1),
+                logit_bias: Default::default(),
+                stop_tokens: None,
+                tools: Vec::new(),
+            })
+            .await?;
+        completion
+            .choices
+            .pop()
+            .and_then(|choice| choice.message.content)
+            .ok_or_else(|| "Caption response had no content".into())
+    }
+}
+
+#[derive(Serialize)]
+struct HttpCaptionRequest<'a> {
+    url: &'a str,
+}
+
+#[derive(Deserialize)]
+struct HttpCaptionResponse {
+    caption: String,
+}
+
+/// Captions images through a local captioning server, which gets `{"url": ...}` posted to it
+/// and answers with `{"caption": ...}`
+pub struct HttpCaptioner {
+    endpoint: String,
+}
+
+impl HttpCaptioner {
+    pub fn new(endpoint: &str) -> Self {
+        HttpCaptioner {
+            endpoint: endpoint.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl Captioner for HttpCaptioner {
+    async fn caption(&self, url: &str) -> CaptionResult {
+        let client = surf::Client::new();
+        let mut request = client.post(&*self.endpoint);
+        request = request.body_json(&HttpCaptionRequest { url })?;
+        let response: HttpCaptionResponse = request.recv_json().await?;
+        Ok(response.caption)
+    }
+}
+
+/// Builds the captioner from `CAPTION_BACKEND` (`openai` or `http`), `CAPTION_MODEL` and
+/// `CAPTION_URL`
+pub fn captioner_from_env(gpt3_token: &str) -> Box<dyn Captioner> {
+    match &*std::env::var("CAPTION_BACKEND").unwrap_or_else(|_| String::from("openai")) {
+        "http" => Box::new(HttpCaptioner::new(
+            &*std::env::var("CAPTION_URL")
+                .unwrap_or_else(|_| String::from("http://localhost:8000/caption")),
+        )),
+        _ => Box::new(OpenAICaptioner::new(
+            gpt3_token,
+            &*std::env::var("CAPTION_MODEL").unwrap_or_else(|_| String::from("gpt-4o-mini")),
+        )),
+    }
+}
+
+/// Whether a file looks like an image we can hand off, going by its extension
+pub fn is_image_filename(filename: &str) -> bool {
+    let filename = filename.to_lowercase();
+    [".png", ".jpg", ".jpeg", ".gif", ".webp"]
+        .iter()
+        .any(|extension| filename.ends_with(extension))
+}